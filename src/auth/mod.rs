@@ -0,0 +1,308 @@
+extern crate crypto;
+extern crate chrono;
+extern crate rustc_serialize;
+extern crate serde_json;
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::Mac;
+use self::crypto::sha2::Sha256;
+use self::crypto::util::fixed_time_eq;
+use self::chrono::Utc;
+use self::rustc_serialize::base64::FromBase64;
+
+use config::AuthSettings;
+
+/// The authenticated user, recorded as `user_id` on the commands it sends
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    #[serde(default)]
+    pub roles: Vec<String>
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(String),
+    Expired,
+    /// the authenticator could not be set up from the settings
+    ConfigError(String),
+    IoError(io::Error)
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "No token given"),
+            AuthError::InvalidToken(reason) => write!(f, "Invalid token: {}", reason),
+            AuthError::Expired => write!(f, "Token has expired"),
+            AuthError::ConfigError(reason) => write!(f, "Invalid auth settings: {}", reason),
+            AuthError::IoError(err) => write!(f, "Could not read token file: {}", err)
+        }
+    }
+}
+
+/// Turns a token presented by a client into an identity
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, token: &str) -> Result<Identity, AuthError>;
+}
+
+/// Verifies HS256 signed JWTs with a shared secret, the user is the `sub` claim
+pub struct JwtAuthenticator {
+    secret: Vec<u8>,
+    issuer: Option<String>
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: String,
+    exp: Option<i64>,
+    iss: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, AuthError> {
+    // base64url without padding, which the decoder accepts
+    part.from_base64().map_err(|err| AuthError::InvalidToken(format!("{}", err)))
+}
+
+impl JwtAuthenticator {
+    pub fn new(secret: &str, issuer: Option<&str>) -> Self {
+        JwtAuthenticator {
+            secret: secret.as_bytes().to_vec(),
+            issuer: issuer.map(String::from)
+        }
+    }
+
+    fn signature(&self, signed_part: &str) -> Vec<u8> {
+        let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+        hmac.input(signed_part.as_bytes());
+        hmac.result().code().to_vec()
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let parts : Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(AuthError::InvalidToken(String::from("Expected header.claims.signature")));
+        }
+
+        let header : JwtHeader = serde_json::from_slice(&decode_part(parts[0])?)
+            .map_err(|err| AuthError::InvalidToken(format!("{}", err)))?;
+        // never let the token choose a weaker algorithm, or none at all
+        if header.alg != "HS256" {
+            return Err(AuthError::InvalidToken(format!("Unsupported alg {}", header.alg)));
+        }
+
+        let signed_part = &token[..parts[0].len() + 1 + parts[1].len()];
+        if !fixed_time_eq(&self.signature(signed_part), &decode_part(parts[2])?) {
+            return Err(AuthError::InvalidToken(String::from("Signature does not match")));
+        }
+
+        let claims : JwtClaims = serde_json::from_slice(&decode_part(parts[1])?)
+            .map_err(|err| AuthError::InvalidToken(format!("{}", err)))?;
+        if let Some(exp) = claims.exp {
+            if exp <= Utc::now().timestamp() {
+                return Err(AuthError::Expired);
+            }
+        }
+        if let Some(ref issuer) = self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(AuthError::InvalidToken(String::from("Unexpected issuer")));
+            }
+        }
+
+        Ok(Identity {
+            user_id: claims.sub,
+            roles: claims.roles
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenFile {
+    #[serde(default)]
+    token: Vec<TokenEntry>
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+    #[serde(flatten)]
+    identity: Identity
+}
+
+/// Fixed tokens read from a TOML file of `[[token]]` tables with `token`, `user_id` and `roles`
+pub struct StaticTokenAuthenticator {
+    tokens: HashMap<String, Identity>
+}
+
+impl StaticTokenAuthenticator {
+    pub fn from_file(path: &str) -> Result<Self, AuthError> {
+        let content = fs::read_to_string(path).map_err(AuthError::IoError)?;
+        let token_file : TokenFile = toml::from_str(&content)
+            .map_err(|err| AuthError::ConfigError(format!("{}", err)))?;
+        Ok(StaticTokenAuthenticator {
+            tokens: token_file.token.into_iter()
+                .map(|entry| (entry.token, entry.identity))
+                .collect()
+        })
+    }
+}
+
+impl Authenticator for StaticTokenAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        // fixed time compare, so timing does not tell how much of a token matched
+        self.tokens.iter()
+            .find(|(known_token, _)| fixed_time_eq(known_token.as_bytes(), token.as_bytes()))
+            .map(|(_, identity)| identity.clone())
+            .ok_or_else(|| AuthError::InvalidToken(String::from("Unknown token")))
+    }
+}
+
+/// Authenticator for `auth.mode`, either `jwt` or `static`
+pub fn create_authenticator(settings: &AuthSettings) -> Result<Box<Authenticator>, AuthError> {
+    match settings.mode.as_str() {
+        "jwt" => {
+            if settings.jwt_secret.is_empty() {
+                return Err(AuthError::ConfigError(String::from("auth.jwt_secret is not set, set it or TOAMEND_AUTH_JWT_SECRET, or use auth.mode = \"static\"")));
+            }
+            let issuer = if settings.jwt_issuer.is_empty() { None } else { Some(settings.jwt_issuer.as_str()) };
+            Ok(Box::new(JwtAuthenticator::new(&settings.jwt_secret, issuer)))
+        },
+        "static" => Ok(Box::new(StaticTokenAuthenticator::from_file(&settings.token_file)?)),
+        mode => Err(AuthError::ConfigError(format!("Unknown auth.mode {}", mode)))
+    }
+}
+
+/// Token of an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header_value: &str) -> Option<&str> {
+    let mut parts = header_value.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None
+    }
+}
+
+/// Percent-decoded `token` parameter of a query string
+pub fn query_token(query: &str) -> Option<String> {
+    query.split('&')
+        .find(|pair| pair.starts_with("token="))
+        .and_then(|pair| percent_decode(&pair["token=".len()..]))
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn query_token_is_percent_decoded() {
+        assert_eq!(query_token("a=1&token=ab%2Bc%2Fd%3D%3D"), Some(String::from("ab+c/d==")));
+        assert_eq!(query_token("token=ab+c/d=="), Some(String::from("ab+c/d==")));
+        assert_eq!(query_token("token=ab%2"), None);
+        assert_eq!(query_token("a=1"), None);
+    }
+
+    use super::rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    const SECRET : &str = "secret";
+
+    fn token(header: Value, claims: Value, secret: &str) -> String {
+        let signed_part = format!("{}.{}", header.to_string().as_bytes().to_base64(URL_SAFE), claims.to_string().as_bytes().to_base64(URL_SAFE));
+        let signature = JwtAuthenticator::new(secret, None).signature(&signed_part);
+        format!("{}.{}", signed_part, signature.to_base64(URL_SAFE))
+    }
+
+    fn hs256(claims: Value) -> String {
+        token(json!({"alg": "HS256", "typ": "JWT"}), claims, SECRET)
+    }
+
+    #[test]
+    fn jwt_gives_subject_and_roles() {
+        let authenticator = JwtAuthenticator::new(SECRET, Some("toamend"));
+        let exp = Utc::now().timestamp() + 60;
+        let identity = authenticator.authenticate(&hs256(json!({"sub": "alice", "roles": ["editor"], "iss": "toamend", "exp": exp}))).unwrap();
+        assert_eq!(identity, Identity{user_id: String::from("alice"), roles: vec![String::from("editor")]});
+    }
+
+    #[test]
+    fn jwt_with_other_secret_is_rejected() {
+        let authenticator = JwtAuthenticator::new(SECRET, None);
+        let forged = token(json!({"alg": "HS256"}), json!({"sub": "alice"}), "other");
+        match authenticator.authenticate(&forged) {
+            Err(AuthError::InvalidToken(_)) => {},
+            other => panic!("expected an invalid token, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn jwt_with_other_alg_is_rejected() {
+        let authenticator = JwtAuthenticator::new(SECRET, None);
+        let unsigned = format!("{}.{}.",
+            json!({"alg": "none"}).to_string().as_bytes().to_base64(URL_SAFE),
+            json!({"sub": "alice"}).to_string().as_bytes().to_base64(URL_SAFE));
+        match authenticator.authenticate(&unsigned) {
+            Err(AuthError::InvalidToken(_)) => {},
+            other => panic!("expected an invalid token, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn expired_jwt_is_rejected() {
+        let authenticator = JwtAuthenticator::new(SECRET, None);
+        let exp = Utc::now().timestamp() - 1;
+        match authenticator.authenticate(&hs256(json!({"sub": "alice", "exp": exp}))) {
+            Err(AuthError::Expired) => {},
+            other => panic!("expected an expired token, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn jwt_of_other_issuer_is_rejected() {
+        let authenticator = JwtAuthenticator::new(SECRET, Some("toamend"));
+        match authenticator.authenticate(&hs256(json!({"sub": "alice", "iss": "other"}))) {
+            Err(AuthError::InvalidToken(_)) => {},
+            other => panic!("expected an invalid token, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn jwt_without_secret_is_a_config_error() {
+        let settings = AuthSettings{mode: String::from("jwt"), jwt_secret: String::new(), ..AuthSettings::default()};
+        match create_authenticator(&settings) {
+            Err(AuthError::ConfigError(_)) => {},
+            Err(err) => panic!("expected a config error, got {}", err),
+            Ok(_) => panic!("expected a config error")
+        }
+    }
+}
@@ -0,0 +1,300 @@
+extern crate toml;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+// used when no path is given and TOAMEND_CONFIG is not set
+const DEFAULT_CONFIG_PATH : &str = "conf/toamend.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    ParseError(toml::de::Error),
+    InvalidValue{key: String, value: String}
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::IoError(err) => write!(f, "Could not read config: {}", err),
+            ConfigError::ParseError(err) => write!(f, "Could not parse config: {}", err),
+            ConfigError::InvalidValue{key, value} => write!(f, "Invalid value {} for {}", value, key)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct KafkaSettings {
+    pub brokers: String,
+    pub command_topic: String,
+    pub event_topic: String,
+    pub subscriber_topic: String,
+    pub result_topic: String,
+    pub dead_letter_topic: String,
+    pub event_group: String
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WorkerSettings {
+    /// consumer group of the command workers
+    pub name: String,
+    /// worker threads of this process, each is assigned its own command partitions
+    pub count: usize,
+    /// directory for an LMDB per owned command partition, empty to share `lmdb.path`
+    pub shard_path: String
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LmdbSettings {
+    pub path: String,
+    pub map_size: u64,
+    pub merkle_seal_interval_secs: i64
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedisSettings {
+    pub url: String,
+    pub pool_size: u32
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WsSettings {
+    pub listen: String,
+    pub max_connections: usize,
+    /// how long a connection waits for the result of a command it sent
+    pub result_timeout_ms: u64
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub listen: String,
+    /// how long a request waits for the result of its command before answering 202
+    pub result_timeout_ms: u64
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// `jwt` or `static`
+    pub mode: String,
+    pub jwt_secret: String,
+    /// checked against the `iss` claim when not empty
+    pub jwt_issuer: String,
+    pub token_file: String
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PolicySettings {
+    /// see `policy::Policy`, every command is allowed when empty
+    pub file: String
+}
+
+/// All settings, read from a TOML file and overridden by `TOAMEND_<SECTION>_<KEY>` env variables
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub kafka: KafkaSettings,
+    pub worker: WorkerSettings,
+    pub lmdb: LmdbSettings,
+    pub redis: RedisSettings,
+    pub ws: WsSettings,
+    pub http: HttpSettings,
+    pub auth: AuthSettings,
+    pub policy: PolicySettings
+}
+
+impl Default for KafkaSettings {
+    fn default() -> Self {
+        KafkaSettings {
+            brokers: String::from("localhost:9092"),
+            command_topic: String::from("test-cmd"),
+            event_topic: String::from("test-evt"),
+            subscriber_topic: String::from("test-evt-subscriber"),
+            result_topic: String::from("test-cmd-result"),
+            dead_letter_topic: String::from("test-cmd-dlq"),
+            event_group: String::from("event-consumer-group")
+        }
+    }
+}
+
+impl Default for WorkerSettings {
+    fn default() -> Self {
+        WorkerSettings {
+            name: String::from("cmd-worker"),
+            count: 1,
+            shard_path: String::new()
+        }
+    }
+}
+
+impl Default for LmdbSettings {
+    fn default() -> Self {
+        LmdbSettings {
+            path: String::from("test-lmdb"),
+            map_size: 2 * 1024 * 1024 * 1024,
+            merkle_seal_interval_secs: 300
+        }
+    }
+}
+
+impl Default for RedisSettings {
+    fn default() -> Self {
+        RedisSettings {
+            url: String::from("redis://localhost"),
+            pool_size: 15
+        }
+    }
+}
+
+impl Default for WsSettings {
+    fn default() -> Self {
+        WsSettings {
+            listen: String::from("127.0.0.1:3012"),
+            max_connections: 28232,
+            result_timeout_ms: 5000
+        }
+    }
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            listen: String::from("127.0.0.1:3080"),
+            result_timeout_ms: 5000
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            mode: String::from("jwt"),
+            jwt_secret: String::new(),
+            jwt_issuer: String::new(),
+            token_file: String::from("conf/tokens.toml")
+        }
+    }
+}
+
+fn override_string(value: &mut String, key: &str) {
+    if let Ok(env_value) = env::var(key) {
+        *value = env_value;
+    }
+}
+
+fn override_parsed<T: FromStr>(value: &mut T, key: &str) -> Result<(), ConfigError> {
+    if let Ok(env_value) = env::var(key) {
+        match env_value.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(_) => return Err(ConfigError::InvalidValue{key: String::from(key), value: env_value})
+        }
+    }
+    Ok(())
+}
+
+impl Settings {
+
+    /// Load settings from `path`, or `TOAMEND_CONFIG`, or `conf/toamend.toml` when it exists,
+    /// then apply env overrides. Without any file the defaults are used.
+    pub fn load(path: Option<&str>) -> Result<Settings, ConfigError> {
+        let file = match path {
+            Some(p) => Some(String::from(p)),
+            None => match env::var("TOAMEND_CONFIG") {
+                Ok(p) => Some(p),
+                Err(_) => if Path::new(DEFAULT_CONFIG_PATH).exists() {
+                    Some(String::from(DEFAULT_CONFIG_PATH))
+                } else {
+                    None
+                }
+            }
+        };
+
+        let mut settings = match file {
+            Some(file_path) => {
+                info!("Reading config {}", file_path);
+                let content = fs::read_to_string(&file_path).map_err(ConfigError::IoError)?;
+                Settings::from_toml(&content)?
+            },
+            None => Settings::default()
+        };
+        settings.apply_env()?;
+        Ok(settings)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Settings, ConfigError> {
+        toml::from_str(content).map_err(ConfigError::ParseError)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_string(&mut self.kafka.brokers, "TOAMEND_KAFKA_BROKERS");
+        override_string(&mut self.kafka.command_topic, "TOAMEND_KAFKA_COMMAND_TOPIC");
+        override_string(&mut self.kafka.event_topic, "TOAMEND_KAFKA_EVENT_TOPIC");
+        override_string(&mut self.kafka.subscriber_topic, "TOAMEND_KAFKA_SUBSCRIBER_TOPIC");
+        override_string(&mut self.kafka.result_topic, "TOAMEND_KAFKA_RESULT_TOPIC");
+        override_string(&mut self.kafka.dead_letter_topic, "TOAMEND_KAFKA_DEAD_LETTER_TOPIC");
+        override_string(&mut self.kafka.event_group, "TOAMEND_KAFKA_EVENT_GROUP");
+        override_string(&mut self.worker.name, "TOAMEND_WORKER_NAME");
+        override_parsed(&mut self.worker.count, "TOAMEND_WORKER_COUNT")?;
+        override_string(&mut self.worker.shard_path, "TOAMEND_WORKER_SHARD_PATH");
+        override_string(&mut self.lmdb.path, "TOAMEND_LMDB_PATH");
+        override_parsed(&mut self.lmdb.map_size, "TOAMEND_LMDB_MAP_SIZE")?;
+        override_parsed(&mut self.lmdb.merkle_seal_interval_secs, "TOAMEND_LMDB_MERKLE_SEAL_INTERVAL_SECS")?;
+        override_string(&mut self.redis.url, "TOAMEND_REDIS_URL");
+        override_parsed(&mut self.redis.pool_size, "TOAMEND_REDIS_POOL_SIZE")?;
+        override_string(&mut self.ws.listen, "TOAMEND_WS_LISTEN");
+        override_parsed(&mut self.ws.max_connections, "TOAMEND_WS_MAX_CONNECTIONS")?;
+        override_parsed(&mut self.ws.result_timeout_ms, "TOAMEND_WS_RESULT_TIMEOUT_MS")?;
+        override_string(&mut self.http.listen, "TOAMEND_HTTP_LISTEN");
+        override_parsed(&mut self.http.result_timeout_ms, "TOAMEND_HTTP_RESULT_TIMEOUT_MS")?;
+        override_string(&mut self.auth.mode, "TOAMEND_AUTH_MODE");
+        override_string(&mut self.auth.jwt_secret, "TOAMEND_AUTH_JWT_SECRET");
+        override_string(&mut self.auth.jwt_issuer, "TOAMEND_AUTH_JWT_ISSUER");
+        override_string(&mut self.auth.token_file, "TOAMEND_AUTH_TOKEN_FILE");
+        override_string(&mut self.policy.file, "TOAMEND_POLICY_FILE");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let settings = Settings::from_toml("[kafka]\nbrokers = \"kafka:9092\"\n\n[lmdb]\nmap_size = 1024\n").unwrap();
+        assert_eq!(settings.kafka.brokers, "kafka:9092");
+        assert_eq!(settings.kafka.command_topic, "test-cmd");
+        assert_eq!(settings.lmdb.map_size, 1024);
+        assert_eq!(settings.lmdb.path, "test-lmdb");
+        assert_eq!(settings.worker.name, "cmd-worker");
+    }
+
+    #[test]
+    fn env_variables_override_the_file() {
+        let mut settings = Settings::default();
+        env::set_var("TOAMEND_WORKER_NAME", "worker-b");
+        env::set_var("TOAMEND_REDIS_POOL_SIZE", "3");
+        settings.apply_env().unwrap();
+        assert_eq!(settings.worker.name, "worker-b");
+        assert_eq!(settings.redis.pool_size, 3);
+
+        env::set_var("TOAMEND_REDIS_POOL_SIZE", "many");
+        let invalid = settings.apply_env();
+        env::remove_var("TOAMEND_WORKER_NAME");
+        env::remove_var("TOAMEND_REDIS_POOL_SIZE");
+        match invalid {
+            Err(ConfigError::InvalidValue{key, value}) => assert_eq!((key.as_str(), value.as_str()), ("TOAMEND_REDIS_POOL_SIZE", "many")),
+            _ => panic!("expected an invalid value")
+        }
+    }
+}
@@ -0,0 +1,156 @@
+extern crate serde_json;
+
+use serde_json::Value;
+
+use domain::{child_path, LedgerEvent};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed
+}
+
+/// A value that differs between two versions, `path` is e.g. `author.name` or `tags[2]`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Value>
+}
+
+/// What changed from one version of a document to another
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VersionDiff {
+    pub id: String,
+    pub from_version: String,
+    pub to_version: String,
+    pub sys: Vec<FieldChange>,
+    pub payload: Vec<FieldChange>
+}
+
+/// Why two versions can not be compared
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DiffError {
+    /// no such document, or it is archived and archived documents were not asked for
+    UnknownDocument{id: String},
+    UnknownVersion{version: String},
+    /// no version to compare with was given and `version` has none before it
+    FirstVersion{version: String},
+    /// a version of `other_id` was asked for as a version of `id`
+    DifferentDocuments{id: String, other_id: String}
+}
+
+impl std::fmt::Display for DiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiffError::UnknownDocument{id} => write!(f, "document {} not found", id),
+            DiffError::UnknownVersion{version} => write!(f, "version {} not found", version),
+            DiffError::FirstVersion{version} => write!(f, "version {} is the first version", version),
+            DiffError::DifferentDocuments{id, other_id} =>
+                write!(f, "version of {} is not a version of {}", other_id, id)
+        }
+    }
+}
+
+fn diff_at(path: &str, from: Option<&Value>, to: Option<&Value>, changes: &mut Vec<FieldChange>) {
+    match (from, to) {
+        (Some(Value::Object(from_object)), Some(Value::Object(to_object))) => {
+            for (key, from_value) in from_object {
+                diff_at(&child_path(path, key), Some(from_value), to_object.get(key), changes);
+            }
+            for (key, to_value) in to_object {
+                if !from_object.contains_key(key) {
+                    diff_at(&child_path(path, key), None, Some(to_value), changes);
+                }
+            }
+        },
+        (Some(Value::Array(from_items)), Some(Value::Array(to_items))) => {
+            for index in 0..from_items.len().max(to_items.len()) {
+                diff_at(&format!("{}[{}]", path, index), from_items.get(index), to_items.get(index), changes);
+            }
+        },
+        (Some(from_value), Some(to_value)) => if from_value != to_value {
+            changes.push(FieldChange{path: String::from(path), kind: ChangeKind::Changed, from: Some(from_value.clone()), to: Some(to_value.clone())});
+        },
+        (Some(from_value), None) =>
+            changes.push(FieldChange{path: String::from(path), kind: ChangeKind::Removed, from: Some(from_value.clone()), to: None}),
+        (None, Some(to_value)) =>
+            changes.push(FieldChange{path: String::from(path), kind: ChangeKind::Added, from: None, to: Some(to_value.clone())}),
+        (None, None) => {}
+    }
+}
+
+/// Changes from one value to another, objects and arrays are compared member by member.
+/// A missing value and null are the same.
+pub fn diff_values(from: &Value, to: &Value) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let present = |value: &Value| if value.is_null() { None } else { Some(value.clone()) };
+    diff_at("", present(from).as_ref(), present(to).as_ref(), &mut changes);
+    changes
+}
+
+/// Changes of the sys fields and the payload between two events of the same document
+pub fn diff_events(from: &LedgerEvent<Value>, to: &LedgerEvent<Value>) -> VersionDiff {
+    let sys_value = |evt: &LedgerEvent<Value>| serde_json::to_value(&evt.sys).unwrap_or(Value::Null);
+    VersionDiff {
+        id: String::from(to.sys.id),
+        from_version: String::from(from.sys.version),
+        to_version: String::from(to.sys.version),
+        sys: diff_values(&sys_value(from), &sys_value(to)),
+        payload: diff_values(from.payload.as_ref().unwrap_or(&Value::Null), to.payload.as_ref().unwrap_or(&Value::Null))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::DocumentState;
+    use domain::fixtures::event;
+
+    fn change(path: &str, kind: ChangeKind, from: Option<Value>, to: Option<Value>) -> FieldChange {
+        FieldChange{path: String::from(path), kind: kind, from: from, to: to}
+    }
+
+    #[test]
+    fn equal_values_have_no_changes() {
+        let value = json!({"title": "a", "tags": ["x", "y"]});
+        assert!(diff_values(&value, &value).is_empty());
+    }
+
+    #[test]
+    fn nested_objects_and_arrays_are_compared_by_member() {
+        let changes = diff_values(
+            &json!({"author": {"name": "a", "age": 1}, "tags": ["x"]}),
+            &json!({"author": {"name": "b", "mail": "m"}, "tags": ["x", "y"]}));
+        assert_eq!(changes, vec![
+            change("author.age", ChangeKind::Removed, Some(json!(1)), None),
+            change("author.name", ChangeKind::Changed, Some(json!("a")), Some(json!("b"))),
+            change("author.mail", ChangeKind::Added, None, Some(json!("m"))),
+            change("tags[1]", ChangeKind::Added, None, Some(json!("y")))
+        ]);
+    }
+
+    #[test]
+    fn null_is_missing() {
+        assert!(diff_values(&Value::Null, &Value::Null).is_empty());
+        assert_eq!(diff_values(&Value::Null, &json!("a")), vec![change("", ChangeKind::Added, None, Some(json!("a")))]);
+    }
+
+    #[test]
+    fn events_diff_sys_and_payload() {
+        let from = event("doc", "v1", DocumentState::Draft, Some(json!({"title": "a"})));
+        let to = event("doc", "v2", DocumentState::Published, Some(json!({"title": "b"})));
+        let version_diff = diff_events(&from, &to);
+        assert_eq!(version_diff.from_version, "v1");
+        assert_eq!(version_diff.to_version, "v2");
+        assert_eq!(version_diff.sys, vec![
+            change("state", ChangeKind::Changed, Some(json!("draft")), Some(json!("published"))),
+            change("version", ChangeKind::Changed, Some(json!("v1")), Some(json!("v2")))
+        ]);
+        assert_eq!(version_diff.payload, vec![change("title", ChangeKind::Changed, Some(json!("a")), Some(json!("b")))]);
+    }
+}
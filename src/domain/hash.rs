@@ -0,0 +1,169 @@
+extern crate crypto;
+
+use self::crypto::digest::Digest;
+use self::crypto::sha2::Sha256;
+use serde_json::Value;
+
+use domain::LedgerEvent;
+
+/// Why a stored event does not verify against its payload or the chain
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChainError {
+    /// written before events were chained, there is nothing to verify
+    MissingHash{version: String},
+    PayloadMismatch{version: String},
+    HashMismatch{version: String},
+    /// `previous_hash` does not match the hash of the previous version
+    BrokenLink{version: String, previous_version: String}
+}
+
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChainError::MissingHash{version} => write!(f, "version {} has no hash", version),
+            ChainError::PayloadMismatch{version} => write!(f, "payload of version {} does not match its checksum", version),
+            ChainError::HashMismatch{version} => write!(f, "version {} does not match its hash", version),
+            ChainError::BrokenLink{version, previous_version} =>
+                write!(f, "version {} is not linked to the hash of version {}", version, previous_version)
+        }
+    }
+}
+
+/// SHA-256 of the serialized payload, a missing payload is digested as `null`.
+/// Object keys serialize in sorted order so equal payloads give equal digests.
+pub fn payload_digest(payload: &Option<Value>) -> String {
+    let mut hasher = Sha256::new();
+    match payload {
+        Some(value) => hasher.input_str(&value.to_string()),
+        None => hasher.input_str("null")
+    }
+    hasher.result_str()
+}
+
+/// SHA-256 over the previous event hash, the sys fields, the payload digest and the patch when there is one
+pub fn event_hash(event: &LedgerEvent<Value>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(event.sys.previous_hash.as_ref().map(|hash| &hash[..]).unwrap_or(""));
+    hasher.input_str("|");
+    match serde_json::to_string(&event.sys) {
+        Ok(sys_json) => hasher.input_str(&sys_json),
+        Err(err) => error!("Error while serializing sys of version={} err={}", event.sys.version, err)
+    }
+    hasher.input_str("|");
+    hasher.input_str(&payload_digest(&event.payload));
+    // only hashed when set, so events without a patch keep their hash
+    if let Some(ref patch) = event.patch {
+        match serde_json::to_string(patch) {
+            Ok(patch_json) => {
+                hasher.input_str("|");
+                hasher.input_str(&patch_json);
+            },
+            Err(err) => error!("Error while serializing patch of version={} err={}", event.sys.version, err)
+        }
+    }
+    hasher.result_str()
+}
+
+/// Check an event against its own payload and, when given, the event it was derived from
+pub fn verify_event(event: &LedgerEvent<Value>, previous: Option<&LedgerEvent<Value>>) -> Result<(), ChainError> {
+    let version = String::from(event.sys.version);
+    let hash = match event.hash {
+        Some(ref hash) => hash,
+        None => return Err(ChainError::MissingHash{version})
+    };
+    if event.sys.payload_checksum != Some(&payload_digest(&event.payload)[..]) {
+        return Err(ChainError::PayloadMismatch{version});
+    }
+    if *hash != event_hash(event) {
+        return Err(ChainError::HashMismatch{version});
+    }
+    if let Some(previous_event) = previous {
+        if event.sys.previous_hash != previous_event.hash {
+            return Err(ChainError::BrokenLink{version, previous_version: String::from(previous_event.sys.version)});
+        }
+    }
+    Ok(())
+}
+
+impl<'a> LedgerEvent<'a, Value> {
+    /// Set the chained hash, call once all sys fields and the payload are final
+    pub fn chained(mut self) -> Self {
+        self.hash = Some(event_hash(&self));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::DocumentState;
+    use domain::fixtures::event;
+
+    #[test]
+    fn payload_digest_ignores_key_order() {
+        let digest = payload_digest(&Some(json!({"a": 1, "b": 2})));
+        assert_eq!(digest, payload_digest(&Some(serde_json::from_str(r#"{"b": 2, "a": 1}"#).unwrap())));
+        assert_ne!(digest, payload_digest(&None));
+    }
+
+    #[test]
+    fn chained_events_verify() {
+        let payload = Some(json!({"title": "first"}));
+        let digest = payload_digest(&payload);
+        let mut first = event("doc", "v1", DocumentState::Draft, payload);
+        first.sys.payload_checksum = Some(&digest);
+        let first = first.chained();
+
+        let mut second = event("doc", "v2", DocumentState::Draft, None);
+        let null_digest = payload_digest(&None);
+        second.sys.payload_checksum = Some(&null_digest);
+        second.sys.previous_version = Some("v1");
+        second.sys.previous_hash = first.hash.clone();
+        let second = second.chained();
+
+        assert_eq!(verify_event(&first, None), Ok(()));
+        assert_eq!(verify_event(&second, Some(&first)), Ok(()));
+    }
+
+    #[test]
+    fn unhashed_event_has_missing_hash() {
+        let evt = event("doc", "v1", DocumentState::Draft, None);
+        assert_eq!(verify_event(&evt, None), Err(ChainError::MissingHash{version: String::from("v1")}));
+    }
+
+    #[test]
+    fn changed_payload_does_not_verify() {
+        let payload = Some(json!({"title": "first"}));
+        let digest = payload_digest(&payload);
+        let mut evt = event("doc", "v1", DocumentState::Draft, payload);
+        evt.sys.payload_checksum = Some(&digest);
+        let mut evt = evt.chained();
+        evt.payload = Some(json!({"title": "changed"}));
+        assert_eq!(verify_event(&evt, None), Err(ChainError::PayloadMismatch{version: String::from("v1")}));
+    }
+
+    #[test]
+    fn changed_sys_does_not_verify() {
+        let digest = payload_digest(&None);
+        let mut evt = event("doc", "v1", DocumentState::Draft, None);
+        evt.sys.payload_checksum = Some(&digest);
+        let mut evt = evt.chained();
+        evt.sys.updated_by = "someone else";
+        assert_eq!(verify_event(&evt, None), Err(ChainError::HashMismatch{version: String::from("v1")}));
+    }
+
+    #[test]
+    fn event_not_linked_to_previous_does_not_verify() {
+        let digest = payload_digest(&None);
+        let mut first = event("doc", "v1", DocumentState::Draft, None);
+        first.sys.payload_checksum = Some(&digest);
+        let first = first.chained();
+        let mut second = event("doc", "v2", DocumentState::Draft, None);
+        second.sys.payload_checksum = Some(&digest);
+        second.sys.previous_version = Some("v1");
+        second.sys.previous_hash = Some(String::from("not the hash of v1"));
+        let second = second.chained();
+        assert_eq!(verify_event(&second, Some(&first)),
+            Err(ChainError::BrokenLink{version: String::from("v2"), previous_version: String::from("v1")}));
+    }
+}
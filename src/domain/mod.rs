@@ -4,6 +4,13 @@ extern crate chrono;
 use self::chrono::{DateTime, Utc};
 use serde_json::Value;
 
+pub mod diff;
+pub mod hash;
+pub mod schema;
+pub mod state;
+
+use self::schema::Violation;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Revision<'a> {
     pub id: &'a str,
@@ -17,7 +24,59 @@ pub enum Action<'a> {
     UPDATE(Revision<'a>),
     DELETE(Revision<'a>),     // delete this asset
     COPY(Revision<'a>),        // special create, init with value from another asset
-    SEAL(Revision<'a>)
+    SEAL(Revision<'a>),
+    PUBLISH(Revision<'a>),     // make this version the live one
+    UNPUBLISH(Revision<'a>),   // withdraw the live version, keep the draft
+    ARCHIVE(Revision<'a>),     // hide from queries, keep the content
+    UNARCHIVE(Revision<'a>),   // back to draft
+    REVERT{id:&'a str, version:&'a str, to_version:&'a str}    // new version with the payload of an earlier one
+}
+
+impl<'a> Action<'a> {
+    /// Name of the action as serialized in `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::CREATE{..} => "CREATE",
+            Action::UPDATE(_) => "UPDATE",
+            Action::DELETE(_) => "DELETE",
+            Action::COPY(_) => "COPY",
+            Action::SEAL(_) => "SEAL",
+            Action::PUBLISH(_) => "PUBLISH",
+            Action::UNPUBLISH(_) => "UNPUBLISH",
+            Action::ARCHIVE(_) => "ARCHIVE",
+            Action::UNARCHIVE(_) => "UNARCHIVE",
+            Action::REVERT{..} => "REVERT"
+        }
+    }
+
+    /// The document revision the action applies to, `None` for CREATE
+    pub fn revision(&self) -> Option<Revision<'a>> {
+        match self {
+            Action::CREATE{..} => None,
+            Action::UPDATE(revision) => Some(revision.clone()),
+            Action::DELETE(revision) => Some(revision.clone()),
+            Action::COPY(revision) => Some(revision.clone()),
+            Action::SEAL(revision) => Some(revision.clone()),
+            Action::PUBLISH(revision) => Some(revision.clone()),
+            Action::UNPUBLISH(revision) => Some(revision.clone()),
+            Action::ARCHIVE(revision) => Some(revision.clone()),
+            Action::UNARCHIVE(revision) => Some(revision.clone()),
+            Action::REVERT{id, version, ..} => Some(Revision{id: *id, version: *version})
+        }
+    }
+}
+
+/// Lifecycle state of a document
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentState {
+    Draft,
+    Published,
+    /// left out of queries unless asked for
+    Archived,
+    /// no further changes, copy to a new document instead
+    Sealed,
+    Deleted
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -30,6 +89,7 @@ pub struct Sys<'a> {
     pub bucket: &'a str,
     pub env: &'a str,
     pub published_count: u32,
+    /// version approved by the last PUBLISH
     pub published_version: Option<&'a str>,
     pub version: &'a str,
     pub previous_version: Option<&'a str>,
@@ -42,7 +102,16 @@ pub struct Sys<'a> {
     pub created_by: &'a str,
     pub updated_at: Option<&'a str>,
     pub updated_by: &'a str,
-    pub payload_checksum: Option<&'a str>
+    pub payload_checksum: Option<&'a str>,
+    /// hash of the event this version was derived from, see `domain::hash`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_hash: Option<String>,
+    /// version of the content type schema the payload was last validated against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type_version: Option<u32>,
+    /// not set on events from before states were recorded, see `LedgerEvent::state`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<DocumentState>
 }
 
 impl<'a> Sys<'a> {
@@ -122,12 +191,28 @@ id": "5KsDBWseXY6QegucYAoacS",
         */
 
 
+/// Change to the latest payload, sent by an UPDATE instead of the full payload
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadPatch {
+    /// RFC 6902 operations
+    JsonPatch(Value),
+    /// RFC 7396 merge patch
+    MergePatch(Value)
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LedgerCommand<'a, T: 'a> {
     pub tracking_id: &'a str,
     pub action: Action<'a>,
     pub payload: Option<T>,
-    pub user_id: Option<&'a str>
+    pub user_id: Option<&'a str>,
+    /// roles of the authenticated user, set by the producer from the token
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// replaces `payload` on an UPDATE, the worker applies it to the latest payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<PayloadPatch>
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -135,17 +220,135 @@ pub struct LedgerEvent<'a, T: 'a> {
     pub event_id: &'a str,
     pub action: Action<'a>,
     pub payload: Option<T>,
-    pub sys: Sys<'a>
+    pub sys: Sys<'a>,
+    /// the patch the payload was computed with, kept for auditing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<PayloadPatch>,
+    /// chained hash over previous hash, sys, payload and patch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// command the event was created by, its result is recorded with the event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracking_id: Option<&'a str>
+}
+
+/// Reason a command was rejected by the command worker
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// the command was based on a version that is no longer the latest
+    OptimisticLock,
+    IdMismatch,
+    /// sealed documents can not be changed, copy to a new document instead
+    Sealed,
+    NotFound,
+    AlreadyExists,
+    NotPublished,
+    /// the action is not allowed in the current state of the document, e.g. updating an archived one
+    InvalidState,
+    InvalidCommand,
+    /// the policy does not allow the user this action on the document
+    Forbidden,
+    /// the payload does not match the schema of its content type, see `violations`
+    SchemaViolation,
+    /// the document store could not be read
+    StoreError
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        CommandError {
+            code: code,
+            message: String::from(message),
+            violations: Vec::new()
+        }
+    }
+
+    pub fn with_violations(code: ErrorCode, message: &str, violations: Vec<Violation>) -> Self {
+        CommandError {
+            violations: violations,
+            ..CommandError::new(code, message)
+        }
+    }
+}
+
+/// Outcome of a `LedgerCommand`, published on the reply topic keyed by `tracking_id`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "status")]
+pub enum CommandResult<'a> {
+    Accepted{tracking_id: &'a str, id: &'a str, version: &'a str, event_id: &'a str},
+    Rejected{tracking_id: &'a str, error: CommandError}
+}
+
+impl<'a> CommandResult<'a> {
+    pub fn tracking_id(&self) -> &'a str {
+        match self {
+            CommandResult::Accepted{tracking_id, ..} => tracking_id,
+            CommandResult::Rejected{tracking_id, ..} => tracking_id
+        }
+    }
+}
+
+/// A command record the worker could not process, kept with enough context to inspect
+/// and re-drive it. Fields are owned since the original payload is arbitrary text.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub payload: Option<String>,
+    pub error: String,
+    pub failed_at: String
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum SubscriptionEvent<'a> {
   Open{conn_id: &'a str},
-  Subscribe{conn_id: &'a str, topic:&'a str},
+  /// `since` is the last version the client has seen, events stored after it are replayed
+  Subscribe{
+    conn_id: &'a str,
+    topic:&'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    since: Option<&'a str>,
+    /// also deliver the events of archived documents
+    #[serde(default)]
+    include_archived: bool
+  },
   Unsubscribe{conn_id: &'a str, topic: &'a str},
   Close{conn_id: &'a str}
 }
 
+impl<'a> SubscriptionEvent<'a> {
+    pub fn conn_id(&self) -> &'a str {
+        match self {
+            SubscriptionEvent::Open{conn_id} => conn_id,
+            SubscriptionEvent::Subscribe{conn_id, ..} => conn_id,
+            SubscriptionEvent::Unsubscribe{conn_id, ..} => conn_id,
+            SubscriptionEvent::Close{conn_id} => conn_id
+        }
+    }
+}
+
+impl<'a, T: 'a> LedgerCommand<'a, T> {
+    /// Kafka key of the command, the commands of a document share a partition and stay in order.
+    /// A CREATE has no document yet, it is keyed by its tracking id.
+    pub fn routing_key(&self) -> &'a str {
+        match self.action.revision() {
+            Some(revision) => revision.id,
+            None => self.tracking_id
+        }
+    }
+}
+
 impl<'a> std::fmt::Display for LedgerCommand<'a, Value> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match serde_json::to_string(&self) {
@@ -156,6 +359,19 @@ impl<'a> std::fmt::Display for LedgerCommand<'a, Value> {
     }
 }
 
+impl<'a> LedgerEvent<'a, Value> {
+    /// State of the document at this version, derived from the event when it has none recorded
+    pub fn state(&self) -> DocumentState {
+        match (self.sys.state, &self.action) {
+            (Some(state), _) => state,
+            (None, Action::DELETE(_)) => DocumentState::Deleted,
+            (None, _) if self.sys.sealed_at.is_some() => DocumentState::Sealed,
+            (None, _) if self.sys.published_version.is_some() => DocumentState::Published,
+            (None, _) => DocumentState::Draft
+        }
+    }
+}
+
 impl<'a> std::fmt::Display for LedgerEvent<'a, Value> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match serde_json::to_string(&self) {
@@ -166,6 +382,30 @@ impl<'a> std::fmt::Display for LedgerEvent<'a, Value> {
     }
 }
 
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl<'a> std::fmt::Display for CommandResult<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match serde_json::to_string(&self) {
+            Ok(json_str) => write!(f, "CommandResult(tracking_id: {} json_data: {})", self.tracking_id(), json_str),
+            Err(err) => write!(f, "CommandResult(tracking_id: {} err: {})", self.tracking_id(), err)
+        }
+        
+    }
+}
+
+impl std::fmt::Display for DeadLetter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "DeadLetter(topic: {} partition: {} offset: {} failed_at: {} error: {} payload: {})",
+            self.topic, self.partition, self.offset, self.failed_at, self.error,
+            self.payload.as_ref().map(|p| &p[..]).unwrap_or(""))
+    }
+}
+
 impl<'a> std::fmt::Display for SubscriptionEvent<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match serde_json::to_string(&self) {
@@ -174,4 +414,94 @@ impl<'a> std::fmt::Display for SubscriptionEvent<'a> {
         }
         
     }
-}
\ No newline at end of file
+}
+
+/// Path of a member of the object at `path`, e.g. `author.name`, shared by diffs and violations
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Events to test with
+#[cfg(test)]
+pub mod fixtures {
+    use serde_json::Value;
+
+    use domain::{Action, DocumentState, LedgerEvent, Sys};
+
+    /// Event of document `id` at `version` in bucket `b` env `e`, unchained and without checksum
+    pub fn event<'a>(id: &'a str, version: &'a str, state: DocumentState, payload: Option<Value>) -> LedgerEvent<'a, Value> {
+        LedgerEvent {
+            sys: Sys {
+                id: id,
+                env: "e",
+                category: "entry",
+                content_type: "article",
+                bucket: "b",
+                version: version,
+                created_by: "user",
+                created_at: Some("2019-10-01T10:00:00Z"),
+                updated_by: "user",
+                updated_at: Some("2019-10-01T10:00:00Z"),
+                first_published_at: None,
+                published_at: None,
+                published_by: None,
+                sealed_at: None,
+                sealed_by: None,
+                previous_version: None,
+                published_version: None,
+                published_count: 0,
+                payload_checksum: None,
+                previous_hash: None,
+                content_type_version: None,
+                state: Some(state)
+            },
+            event_id: version,
+            action: Action::CREATE{category: "entry", content_type: "article", bucket: "b", env: "e"},
+            payload: payload,
+            patch: None,
+            hash: None,
+            tracking_id: None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command<'a>(action: Action<'a>) -> LedgerCommand<'a, Value> {
+        LedgerCommand{tracking_id: "t", action: action, payload: None, user_id: None, roles: vec![], patch: None}
+    }
+
+    #[test]
+    fn publish_commands_name_the_revision() {
+        let cmd_json = r#"{"tracking_id": "t", "action": {"type": "PUBLISH", "id": "doc", "version": "v1"}, "payload": null, "user_id": "user"}"#;
+        match serde_json::from_str::<LedgerCommand<Value>>(cmd_json).unwrap().action {
+            Action::PUBLISH(Revision{id, version}) => assert_eq!((id, version), ("doc", "v1")),
+            _ => panic!("expected a PUBLISH")
+        }
+        assert_eq!(serde_json::to_value(&Action::UNPUBLISH(Revision{id: "doc", version: "v2"})).unwrap(),
+            json!({"type": "UNPUBLISH", "id": "doc", "version": "v2"}));
+    }
+
+    #[test]
+    fn results_are_tagged_with_their_status() {
+        let rejected = CommandResult::Rejected{tracking_id: "t", error: CommandError::new(ErrorCode::Sealed, "Document is sealed")};
+        assert_eq!(serde_json::to_value(&rejected).unwrap(),
+            json!({"status": "Rejected", "tracking_id": "t", "error": {"code": "Sealed", "message": "Document is sealed"}}));
+        let accepted = CommandResult::Accepted{tracking_id: "t", id: "doc", version: "v2", event_id: "e"};
+        assert_eq!(serde_json::to_value(&accepted).unwrap()["status"], json!("Accepted"));
+        assert_eq!(accepted.tracking_id(), "t");
+    }
+
+    #[test]
+    fn commands_are_keyed_by_document() {
+        assert_eq!(command(Action::UPDATE(Revision{id: "doc", version: "v1"})).routing_key(), "doc");
+        assert_eq!(command(Action::REVERT{id: "doc", version: "v2", to_version: "v1"}).routing_key(), "doc");
+        assert_eq!(command(Action::CREATE{category: "entry", content_type: "article", bucket: "b", env: "e"}).routing_key(), "t");
+    }
+}
@@ -0,0 +1,233 @@
+extern crate chrono;
+
+use self::chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+use domain::child_path;
+
+/// Category of the ledger documents that define content types, their payload is a `ContentTypeSchema`
+pub const SCHEMA_CATEGORY : &str = "content_type";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FieldType {
+    Text,
+    Integer,
+    Number,
+    Boolean,
+    /// RFC 3339 date time
+    Date,
+    Object,
+    Array
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldSchema {
+    /// key in the payload, not used for array items
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub required: bool,
+    /// characters of a Text, items of an Array
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    /// the only values allowed
+    #[serde(default, rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub enum_values: Option<Vec<Value>>,
+    /// fields of an Object, an Object without fields takes any content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldSchema>,
+    /// schema of the items of an Array
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<FieldSchema>>
+}
+
+/// Shape of the payloads of a content type
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContentTypeSchema {
+    /// the `content_type` of the documents it applies to
+    pub name: String,
+    /// raise when the shape changes, documents record the version they were validated against
+    #[serde(default = "first_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub fields: Vec<FieldSchema>,
+    /// by default a payload key without a field is a violation
+    #[serde(default)]
+    pub allow_unknown_fields: bool
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// A payload value that does not match its field, `path` is e.g. `author.name` or `tags[2]`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub path: String,
+    pub message: String
+}
+
+fn violation(violations: &mut Vec<Violation>, path: &str, message: String) {
+    violations.push(Violation {
+        path: String::from(path),
+        message: message
+    });
+}
+
+fn type_matches(field_type: FieldType, value: &Value) -> bool {
+    match field_type {
+        FieldType::Text => value.is_string(),
+        FieldType::Integer => value.is_i64() || value.is_u64(),
+        FieldType::Number => value.is_number(),
+        FieldType::Boolean => value.is_boolean(),
+        FieldType::Date => value.as_str().map_or(false, |date_str| date_str.parse::<DateTime<Utc>>().is_ok()),
+        FieldType::Object => value.is_object(),
+        FieldType::Array => value.is_array()
+    }
+}
+
+fn validate_value(field: &FieldSchema, value: &Value, path: &str, allow_unknown_fields: bool, violations: &mut Vec<Violation>) {
+    if !type_matches(field.field_type, value) {
+        return violation(violations, path, format!("Expected {:?}", field.field_type));
+    }
+    if let Some(ref enum_values) = field.enum_values {
+        if !enum_values.contains(value) {
+            violation(violations, path, format!("Must be one of {}", Value::Array(enum_values.clone())));
+        }
+    }
+    match value {
+        Value::String(text) => if let Some(max_length) = field.max_length {
+            if text.chars().count() > max_length {
+                violation(violations, path, format!("Longer than {} characters", max_length));
+            }
+        },
+        Value::Array(items) => {
+            if let Some(max_length) = field.max_length {
+                if items.len() > max_length {
+                    violation(violations, path, format!("More than {} items", max_length));
+                }
+            }
+            if let Some(ref item_schema) = field.items {
+                for (index, item) in items.iter().enumerate() {
+                    validate_value(item_schema, item, &format!("{}[{}]", path, index), allow_unknown_fields, violations);
+                }
+            }
+        },
+        Value::Object(object) if !field.fields.is_empty() => validate_fields(&field.fields, object, path, allow_unknown_fields, violations),
+        _ => {}
+    }
+}
+
+fn validate_fields(fields: &[FieldSchema], object: &Map<String, Value>, path: &str, allow_unknown_fields: bool, violations: &mut Vec<Violation>) {
+    for field in fields {
+        let field_path = child_path(path, &field.id);
+        match object.get(&field.id) {
+            None | Some(Value::Null) => if field.required {
+                violation(violations, &field_path, String::from("Required"));
+            },
+            Some(value) => validate_value(field, value, &field_path, allow_unknown_fields, violations)
+        }
+    }
+    if !allow_unknown_fields {
+        for key in object.keys() {
+            if !fields.iter().any(|field| &field.id == key) {
+                violation(violations, &child_path(path, key), String::from("Unknown field"));
+            }
+        }
+    }
+}
+
+impl ContentTypeSchema {
+
+    /// Every violation of the payload, empty when it is valid
+    pub fn validate(&self, payload: &Option<Value>) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        match payload {
+            Some(Value::Object(object)) => validate_fields(&self.fields, object, "", self.allow_unknown_fields, &mut violations),
+            None | Some(Value::Null) => validate_fields(&self.fields, &Map::new(), "", self.allow_unknown_fields, &mut violations),
+            Some(_) => violation(&mut violations, "", String::from("Expected an object"))
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn schema(json: Value) -> ContentTypeSchema {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn object_without_fields_takes_any_content() {
+        let schema = schema(json!({
+            "name": "article",
+            "fields": [{"id": "meta", "type": "Object"}]
+        }));
+        assert!(schema.validate(&Some(json!({"meta": {"any": {"nested": [1, 2]}}}))).is_empty());
+        assert_eq!(schema.validate(&Some(json!({"meta": 1}))), vec![Violation {
+            path: String::from("meta"),
+            message: String::from("Expected Object")
+        }]);
+    }
+
+    fn article_schema() -> ContentTypeSchema {
+        schema(json!({
+            "name": "article",
+            "fields": [
+                {"id": "title", "type": "Text", "required": true, "max_length": 5},
+                {"id": "status", "type": "Text", "enum": ["draft", "final"]},
+                {"id": "published", "type": "Date"},
+                {"id": "author", "type": "Object", "fields": [{"id": "name", "type": "Text", "required": true}]},
+                {"id": "tags", "type": "Array", "max_length": 2, "items": {"type": "Integer"}}
+            ]
+        }))
+    }
+
+    fn paths(violations: Vec<Violation>) -> Vec<String> {
+        violations.into_iter().map(|violation| violation.path).collect()
+    }
+
+    #[test]
+    fn valid_payload_has_no_violations() {
+        let payload = json!({
+            "title": "Hello",
+            "status": "final",
+            "published": "2019-10-01T10:00:00Z",
+            "author": {"name": "a"},
+            "tags": [1, 2]
+        });
+        assert!(article_schema().validate(&Some(payload)).is_empty());
+    }
+
+    #[test]
+    fn violations_are_reported_by_path() {
+        let payload = json!({
+            "title": "Too long",
+            "status": "other",
+            "published": "yesterday",
+            "author": {"mail": "m"},
+            "tags": [1, "two", 3],
+            "extra": true
+        });
+        assert_eq!(paths(article_schema().validate(&Some(payload))),
+            vec!["title", "status", "published", "author.name", "author.mail", "tags", "tags[1]", "extra"]);
+    }
+
+    #[test]
+    fn missing_payload_only_violates_required_fields() {
+        assert_eq!(paths(article_schema().validate(&None)), vec!["title"]);
+        assert_eq!(paths(article_schema().validate(&Some(json!([])))), vec![""]);
+    }
+
+    #[test]
+    fn unknown_fields_can_be_allowed() {
+        let mut schema = article_schema();
+        schema.allow_unknown_fields = true;
+        assert!(schema.validate(&Some(json!({"title": "a", "author": {"name": "a", "mail": "m"}, "extra": 1}))).is_empty());
+    }
+}
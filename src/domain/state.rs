@@ -0,0 +1,179 @@
+use serde_json::Value;
+
+use domain::{Action, CommandError, DocumentState, ErrorCode, LedgerEvent};
+
+fn not_allowed(action: &Action, state: DocumentState) -> CommandError {
+    CommandError::new(ErrorCode::InvalidState, &format!("{} is not allowed on a {:?} document", action.name(), state))
+}
+
+/// State of the document after `action`, `state` is `None` when the document does not exist.
+/// For COPY it is the state of the new document, the copied one is left as it is.
+pub fn transition(state: Option<DocumentState>, action: &Action) -> Result<DocumentState, CommandError> {
+    match (state, action) {
+        (None, Action::CREATE{..}) => Ok(DocumentState::Draft),
+        (Some(_), Action::CREATE{..}) => Err(CommandError::new(ErrorCode::AlreadyExists, "Tried to create a new value with an already existing id or version")),
+        (None, _) => Err(CommandError::new(ErrorCode::NotFound, "Document not found")),
+        (Some(state @ DocumentState::Deleted), _) => Err(not_allowed(action, state)),
+        // sealed content lives on in copies only
+        (Some(DocumentState::Sealed), Action::COPY(_)) => Ok(DocumentState::Draft),
+        (Some(DocumentState::Sealed), _) => Err(CommandError::new(ErrorCode::Sealed, "Document is sealed. Copy data to new document.")),
+        (Some(DocumentState::Archived), Action::UNARCHIVE(_)) => Ok(DocumentState::Draft),
+        (Some(DocumentState::Archived), Action::DELETE(_)) => Ok(DocumentState::Deleted),
+        (Some(state @ DocumentState::Archived), _) => Err(not_allowed(action, state)),
+        (Some(state), Action::UNARCHIVE(_)) => Err(not_allowed(action, state)),
+        // unpublish first, archived documents are never live
+        (Some(state @ DocumentState::Published), Action::ARCHIVE(_)) => Err(not_allowed(action, state)),
+        (Some(DocumentState::Draft), Action::UNPUBLISH(_)) => Err(CommandError::new(ErrorCode::NotPublished, "Document is not published")),
+        (Some(state), Action::UPDATE(_)) | (Some(state), Action::REVERT{..}) => Ok(state),
+        (Some(_), Action::COPY(_)) | (Some(_), Action::UNPUBLISH(_)) => Ok(DocumentState::Draft),
+        (Some(_), Action::PUBLISH(_)) => Ok(DocumentState::Published),
+        (Some(_), Action::SEAL(_)) => Ok(DocumentState::Sealed),
+        (Some(_), Action::ARCHIVE(_)) => Ok(DocumentState::Archived),
+        (Some(_), Action::DELETE(_)) => Ok(DocumentState::Deleted)
+    }
+}
+
+/// Check an action on a revision against the latest version of its document,
+/// returns the state of the document after the action
+pub fn check_revision(latest: &LedgerEvent<Value>, action: &Action) -> Result<DocumentState, CommandError> {
+    match action.revision() {
+        Some(ref revision) if latest.sys.version != revision.version =>
+            Err(CommandError::new(ErrorCode::OptimisticLock, "Optimistic lock error, retry change on latest data")),
+        Some(ref revision) if latest.sys.id != revision.id =>
+            Err(CommandError::new(ErrorCode::IdMismatch, "internal id mismatch")),
+        _ => transition(Some(latest.state()), action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::Revision;
+    use domain::fixtures::event;
+
+    const REVISION : Revision<'static> = Revision{id: "doc", version: "v1"};
+
+    fn actions() -> Vec<Action<'static>> {
+        vec![
+            Action::CREATE{category: "entry", content_type: "article", bucket: "b", env: "e"},
+            Action::UPDATE(REVISION),
+            Action::DELETE(REVISION),
+            Action::COPY(REVISION),
+            Action::SEAL(REVISION),
+            Action::PUBLISH(REVISION),
+            Action::UNPUBLISH(REVISION),
+            Action::ARCHIVE(REVISION),
+            Action::UNARCHIVE(REVISION),
+            Action::REVERT{id: "doc", version: "v1", to_version: "v0"}
+        ]
+    }
+
+    /// Outcome of every action in `actions()` order
+    fn assert_transitions(state: Option<DocumentState>, expected: [Result<DocumentState, ErrorCode>; 10]) {
+        for (action, expected) in actions().iter().zip(expected.iter()) {
+            let outcome = transition(state, action).map_err(|err| err.code);
+            assert_eq!(&outcome, expected, "{} on {:?}", action.name(), state);
+        }
+    }
+
+    #[test]
+    fn transitions_of_missing_document() {
+        let not_found = Err(ErrorCode::NotFound);
+        assert_transitions(None, [Ok(DocumentState::Draft), not_found, not_found, not_found, not_found,
+            not_found, not_found, not_found, not_found, not_found]);
+    }
+
+    #[test]
+    fn transitions_of_draft() {
+        assert_transitions(Some(DocumentState::Draft), [
+            Err(ErrorCode::AlreadyExists),
+            Ok(DocumentState::Draft),
+            Ok(DocumentState::Deleted),
+            Ok(DocumentState::Draft),
+            Ok(DocumentState::Sealed),
+            Ok(DocumentState::Published),
+            Err(ErrorCode::NotPublished),
+            Ok(DocumentState::Archived),
+            Err(ErrorCode::InvalidState),
+            Ok(DocumentState::Draft)
+        ]);
+    }
+
+    #[test]
+    fn transitions_of_published() {
+        assert_transitions(Some(DocumentState::Published), [
+            Err(ErrorCode::AlreadyExists),
+            Ok(DocumentState::Published),
+            Ok(DocumentState::Deleted),
+            Ok(DocumentState::Draft),
+            Ok(DocumentState::Sealed),
+            Ok(DocumentState::Published),
+            Ok(DocumentState::Draft),
+            Err(ErrorCode::InvalidState),
+            Err(ErrorCode::InvalidState),
+            Ok(DocumentState::Published)
+        ]);
+    }
+
+    #[test]
+    fn transitions_of_archived() {
+        let invalid = Err(ErrorCode::InvalidState);
+        assert_transitions(Some(DocumentState::Archived), [
+            Err(ErrorCode::AlreadyExists),
+            invalid,
+            Ok(DocumentState::Deleted),
+            invalid,
+            invalid,
+            invalid,
+            invalid,
+            invalid,
+            Ok(DocumentState::Draft),
+            invalid
+        ]);
+    }
+
+    #[test]
+    fn transitions_of_sealed() {
+        let sealed = Err(ErrorCode::Sealed);
+        assert_transitions(Some(DocumentState::Sealed), [
+            Err(ErrorCode::AlreadyExists),
+            sealed,
+            sealed,
+            Ok(DocumentState::Draft),
+            sealed,
+            sealed,
+            sealed,
+            sealed,
+            sealed,
+            sealed
+        ]);
+    }
+
+    #[test]
+    fn transitions_of_deleted() {
+        let invalid = Err(ErrorCode::InvalidState);
+        assert_transitions(Some(DocumentState::Deleted), [Err(ErrorCode::AlreadyExists), invalid, invalid, invalid,
+            invalid, invalid, invalid, invalid, invalid, invalid]);
+    }
+
+    #[test]
+    fn revision_must_be_the_latest_version() {
+        let latest = event("doc", "v2", DocumentState::Draft, None);
+        let outcome = check_revision(&latest, &Action::UPDATE(Revision{id: "doc", version: "v1"}));
+        assert_eq!(outcome.map_err(|err| err.code), Err(ErrorCode::OptimisticLock));
+    }
+
+    #[test]
+    fn revision_must_be_of_the_document() {
+        let latest = event("doc", "v2", DocumentState::Draft, None);
+        let outcome = check_revision(&latest, &Action::UPDATE(Revision{id: "other", version: "v2"}));
+        assert_eq!(outcome.map_err(|err| err.code), Err(ErrorCode::IdMismatch));
+    }
+
+    #[test]
+    fn matching_revision_transitions_the_latest_state() {
+        let latest = event("doc", "v2", DocumentState::Published, None);
+        let outcome = check_revision(&latest, &Action::UNPUBLISH(Revision{id: "doc", version: "v2"}));
+        assert_eq!(outcome.map_err(|err| err.code), Ok(DocumentState::Draft));
+    }
+}
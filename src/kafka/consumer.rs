@@ -2,6 +2,8 @@ extern crate clap;
 extern crate futures;
 extern crate rdkafka;
 
+use std::sync::Arc;
+
 use self::rdkafka::client::ClientContext;
 use self::rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use self::rdkafka::consumer::stream_consumer::StreamConsumer;
@@ -10,9 +12,17 @@ use self::rdkafka::topic_partition_list::Offset;
 use self::rdkafka::error::KafkaResult;
 
 
+/// Told which partitions a consumer owns after a rebalance
+pub trait RebalanceListener: Send + Sync {
+    fn assigned(&self, partitions: &[i32]);
+    fn revoked(&self);
+}
+
 // A simple context to customize the consumer behavior and print a log line every time
 // offsets are committed
-pub struct LoggingConsumerContext;
+pub struct LoggingConsumerContext {
+    rebalance_listener: Option<Arc<RebalanceListener>>
+}
 
 impl ClientContext for LoggingConsumerContext {}
 
@@ -76,9 +86,16 @@ impl ConsumerContext for LoggingConsumerContext {
                     }
                     
                 }                
+                if let Some(ref listener) = self.rebalance_listener {
+                    let partitions : Vec<i32> = topic_partition_list.elements().iter().map(|e| e.partition()).collect();
+                    listener.assigned(&partitions);
+                }
             },
             Rebalance::Revoke => {
                 info!("All partitions are revoked");
+                if let Some(ref listener) = self.rebalance_listener {
+                    listener.revoked();
+                }
             },
             Rebalance::Error(err) => {
                 error!("Post rebalance error {}", err);
@@ -93,7 +110,14 @@ impl ConsumerContext for LoggingConsumerContext {
 pub type LoggingConsumer = StreamConsumer<LoggingConsumerContext>;
 
 pub fn create_consumer(brokers: &str, group_id: &str, topics: &[&str]) -> LoggingConsumer {
-    let context = LoggingConsumerContext;
+    create_consumer_with_listener(brokers, group_id, topics, None)
+}
+
+/// Consumer that tells `rebalance_listener` about the partitions it is assigned
+pub fn create_consumer_with_listener(brokers: &str, group_id: &str, topics: &[&str], rebalance_listener: Option<Arc<RebalanceListener>>) -> LoggingConsumer {
+    let context = LoggingConsumerContext {
+        rebalance_listener: rebalance_listener
+    };
 
     let consumer: LoggingConsumer = ClientConfig::new()
         .set("group.id", group_id)
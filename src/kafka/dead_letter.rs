@@ -0,0 +1,217 @@
+extern crate rdkafka;
+extern crate chrono;
+extern crate uuid;
+extern crate serde_json;
+
+use std::time::Duration;
+use futures::Future;
+
+use self::rdkafka::Message;
+use self::rdkafka::config::ClientConfig;
+use self::rdkafka::consumer::{Consumer, CommitMode};
+use self::rdkafka::consumer::base_consumer::BaseConsumer;
+use self::rdkafka::message::{Headers, OwnedHeaders};
+use self::rdkafka::producer::{FutureProducer, FutureRecord};
+use self::chrono::Utc;
+use self::uuid::Uuid;
+
+use domain::DeadLetter;
+use kafka::producer::create_producer;
+
+// stop reading the dead letter topic when it has been idle this long
+const IDLE_TIMEOUT_MS : u64 = 5000;
+
+/// Capture a message that could not be processed together with the reason
+pub fn dead_letter_from_message<M: Message>(m: &M, error: &str) -> DeadLetter {
+    let mut headers = Vec::new();
+    if let Some(message_headers) = m.headers() {
+        for idx in 0..message_headers.count() {
+            if let Some((name, value)) = message_headers.get(idx) {
+                headers.push((String::from(name), String::from_utf8_lossy(value).into_owned()));
+            }
+        }
+    }
+
+    DeadLetter {
+        topic: String::from(m.topic()),
+        partition: m.partition(),
+        offset: m.offset(),
+        key: m.key().map(|key| String::from_utf8_lossy(key).into_owned()),
+        headers: headers,
+        payload: m.payload().map(|payload| String::from_utf8_lossy(payload).into_owned()),
+        error: String::from(error),
+        failed_at: Utc::now().to_rfc3339()
+    }
+}
+
+/// Write a dead letter and wait for the broker to acknowledge it, the source offset
+/// should only be stored when this returns true
+pub fn produce_dead_letter(producer: &FutureProducer, dead_letter_topic: &str, dead_letter: &DeadLetter) -> bool {
+    match serde_json::to_string(dead_letter) {
+        Result::Ok(val) => {
+            let key = format!("{}|{}|{}", dead_letter.topic, dead_letter.partition, dead_letter.offset);
+            let delivery = producer.send(
+                FutureRecord::to(dead_letter_topic)
+                    .payload(&val)
+                    .key(&key),
+                5000
+            );
+            match delivery.wait() {
+                Ok(Ok(_)) => true,
+                Ok(Err((err, _))) => {
+                    error!("Could not write dead letter {} err={}", key, err);
+                    false
+                },
+                Err(_) => {
+                    error!("Dead letter delivery canceled {}", key);
+                    false
+                }
+            }
+        }
+        Result::Err(err) => {
+            error!("Could not serialize dead letter err={}", err);
+            false
+        }
+    }
+}
+
+fn create_dead_letter_consumer(brokers: &str, group_id: &str, dead_letter_topic: &str) -> BaseConsumer {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("group.id", group_id)
+        .set("bootstrap.servers", brokers)
+        .set("enable.partition.eof", "false")
+        .set("auto.offset.reset", "earliest")
+        .set("enable.auto.commit", "false")
+        .create()
+        .expect("Dead letter consumer creation failed");
+
+    consumer.subscribe(&[dead_letter_topic]).expect("Can't subscribe to dead letter topic");
+
+    consumer
+}
+
+/// Read dead letters from the beginning of the topic without committing anything
+pub fn list_dead_letters(brokers: &str, dead_letter_topic: &str, max: usize) -> Vec<DeadLetter> {
+    // a throw away group so that listing never moves the re-drive position
+    let group_id = format!("dead-letter-inspect-{}", Uuid::new_v4().to_hyphenated());
+    let consumer = create_dead_letter_consumer(brokers, &group_id, dead_letter_topic);
+
+    let mut result = Vec::new();
+    while result.len() < max {
+        match consumer.poll(Duration::from_millis(IDLE_TIMEOUT_MS)) {
+            None => break,
+            Some(Err(err)) => warn!("Kafka error while reading dead letters: {}", err),
+            Some(Ok(m)) => match m.payload_view::<str>() {
+                Some(Ok(payload)) => match serde_json::from_str::<DeadLetter>(payload) {
+                    Ok(dead_letter) => result.push(dead_letter),
+                    Err(err) => warn!("Unreadable dead letter offset={} err={}", m.offset(), err)
+                },
+                _ => warn!("Empty dead letter offset={}", m.offset())
+            }
+        }
+    }
+    result
+}
+
+/// Send dead letters back to the topic they came from. Without a position every pending
+/// record is re-driven and committed in the re-drive group so it is not sent twice, with
+/// a source (partition, offset) only that record is sent and nothing is committed.
+/// Returns the number of re-driven records.
+pub fn redrive_dead_letters(brokers: &str, dead_letter_topic: &str, only_position: Option<(i32, i64)>) -> usize {
+    let group_id = match only_position {
+        Some(_) => format!("dead-letter-redrive-{}", Uuid::new_v4().to_hyphenated()),
+        None => String::from("dead-letter-redrive")
+    };
+    let consumer = create_dead_letter_consumer(brokers, &group_id, dead_letter_topic);
+    let producer = create_producer(brokers);
+
+    let mut count = 0;
+    loop {
+        match consumer.poll(Duration::from_millis(IDLE_TIMEOUT_MS)) {
+            None => break,
+            Some(Err(err)) => warn!("Kafka error while reading dead letters: {}", err),
+            Some(Ok(m)) => {
+                let dead_letter = match m.payload_view::<str>() {
+                    Some(Ok(payload)) => serde_json::from_str::<DeadLetter>(payload).ok(),
+                    _ => None
+                };
+                match (dead_letter, only_position) {
+                    (Some(dl), Some((partition, offset))) => {
+                        if dl.partition == partition && dl.offset == offset {
+                            if redrive(&producer, &dl) {
+                                count += 1;
+                            }
+                            break;
+                        }
+                    },
+                    (Some(dl), None) => {
+                        if !redrive(&producer, &dl) {
+                            // keep the position so that a later run retries this record
+                            break;
+                        }
+                        count += 1;
+                        if let Err(err) = consumer.commit_message(&m, CommitMode::Sync) {
+                            warn!("Error while committing dead letter offset={} err={}", m.offset(), err);
+                        }
+                    },
+                    (None, _) => warn!("Skipping unreadable dead letter offset={}", m.offset())
+                }
+            }
+        }
+    }
+    count
+}
+
+fn redrive(producer: &FutureProducer, dead_letter: &DeadLetter) -> bool {
+    let payload = match dead_letter.payload {
+        Some(ref payload) => payload,
+        None => {
+            warn!("Dead letter offset={} has no payload to re-drive", dead_letter.offset);
+            return true;
+        }
+    };
+
+    let mut headers = OwnedHeaders::new();
+    for (name, value) in &dead_letter.headers {
+        headers = headers.add(name, value.as_str());
+    }
+
+    let mut record = FutureRecord::to(&dead_letter.topic)
+        .payload(payload)
+        .headers(headers);
+    if let Some(ref key) = dead_letter.key {
+        record = record.key(key);
+    }
+
+    match producer.send(record, 5000).wait() {
+        Ok(Ok(_)) => {
+            info!("Re-drove topic={} partition={} offset={}", dead_letter.topic, dead_letter.partition, dead_letter.offset);
+            true
+        },
+        Ok(Err((err, _))) => {
+            error!("Could not re-drive offset={} err={}", dead_letter.offset, err);
+            false
+        },
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::rdkafka::message::{OwnedMessage, Timestamp};
+
+    #[test]
+    fn dead_letter_keeps_the_record_and_the_reason() {
+        let headers = OwnedHeaders::new().add("source", "cli");
+        let message = OwnedMessage::new(Some(b"{not json".to_vec()), Some(b"t1".to_vec()), String::from("commands"),
+            Timestamp::NotAvailable, 2, 42, Some(headers));
+        let dead_letter = dead_letter_from_message(&message, "Could not parse command");
+
+        assert_eq!((dead_letter.topic.as_str(), dead_letter.partition, dead_letter.offset), ("commands", 2, 42));
+        assert_eq!(dead_letter.key, Some(String::from("t1")));
+        assert_eq!(dead_letter.headers, vec![(String::from("source"), String::from("cli"))]);
+        assert_eq!(dead_letter.payload, Some(String::from("{not json")));
+        assert_eq!(dead_letter.error, "Could not parse command");
+    }
+}
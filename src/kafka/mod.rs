@@ -3,66 +3,45 @@ extern crate serde_json;
 extern crate uuid;
 extern crate chrono;
 extern crate crypto;
+extern crate json_patch;
 
 pub mod consumer;
 pub mod producer;
+pub mod dead_letter;
+pub mod reply;
+pub mod shard;
 
-use std::io::{Error, ErrorKind};
+use std::sync::Arc;
 use std::thread;
 pub use self::producer::produce_command;
 
-use self::producer::create_producer;
-use self::consumer::create_consumer;
-use futures::Stream;
+use self::producer::{create_producer, produce_command_result};
+use self::dead_letter::{dead_letter_from_message, produce_dead_letter};
+use self::consumer::{create_consumer, create_consumer_with_listener, RebalanceListener};
+use self::shard::{Shards, WorkerShards};
+use futures::{Future, Stream};
 use self::rdkafka::Message;
 use self::rdkafka::consumer::{Consumer};
 
 use self::rdkafka::producer::{FutureProducer, FutureRecord};
 use self::chrono::Utc;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
 use self::uuid::Uuid;
 
 use self::consumer::LoggingConsumer;
 
-use domain::{LedgerCommand, LedgerEvent, Action, Sys, SubscriptionEvent};
+use domain::{LedgerCommand, LedgerEvent, Action, Sys, DocumentState, SubscriptionEvent, CommandResult, CommandError, ErrorCode, PayloadPatch};
+use domain::hash::payload_digest;
+use domain::schema::{ContentTypeSchema, SCHEMA_CATEGORY};
+use domain::state::{check_revision, transition};
 use serde_json::Value;
 
-use lmdb_store::create_context;
+use lmdb_store::{create_context, LmdbContext};
+use policy::{Policy, Scope};
+use config::{Settings, KafkaSettings};
 use lmdb_rs::core::MdbError; // TODO remove this dependency later
 
 
 
-#[derive(Copy, Clone)]
-pub struct WorkerConfig<'a> {
-    pub name: &'a str,
-    pub topics: &'a [&'a str], 
-    pub publish_events_topic: &'a Option<& 'a str>
-}
-
-#[derive(Copy, Clone)]
-pub struct KafkaConfig<'a> {
-    pub brokers: &'a str,
-    pub workers: &'a [&'a WorkerConfig<'a>]
-} 
-
-const KAFKA_BROKERS : &str = "localhost:9092";
-
-const KAFKA_CMD_CONFIG : KafkaConfig = KafkaConfig {
-    brokers: KAFKA_BROKERS,
-    workers: &[&WorkerConfig{name: "cmd-worker", topics: &["test-cmd"], publish_events_topic: &Some("test-evt")}]
-};
-
-const KAFKA_EVT_CONFIG : KafkaConfig = KafkaConfig {
-    brokers: KAFKA_BROKERS,
-    workers: &[&WorkerConfig{name: "evt-worker", topics: &["test-evt"], publish_events_topic: &Option::None}]
-};
-
-pub const KAFKA_EVT_SUBSCRIBERS_CONFIG : KafkaConfig = KafkaConfig {
-    brokers: KAFKA_BROKERS,
-    workers: &[&WorkerConfig{name: "evt-subscriber", topics: &["test-evt-subscriber"], publish_events_topic: &Option::None}]
-};
-
 pub trait LedgerEvents {
     fn on_event(&self, event: &LedgerEvent<Value>) {}
     fn on_subscription_event(&self, event: &SubscriptionEvent) {}
@@ -70,22 +49,24 @@ pub trait LedgerEvents {
 
 pub struct LedgerEventsConsumer<'a> {
     hooks: Vec<Box<&'a LedgerEvents>>,
-    consumer: LoggingConsumer
+    consumer: LoggingConsumer,
+    event_topic: String,
+    subscriber_topic: String
 }
 
 impl<'a> LedgerEventsConsumer<'a> {
-    pub fn new(group_id: &str, include_subscriber_stream: bool) -> Self {
-        if include_subscriber_stream {
-            Self {
-                hooks: Vec::new(),
-                consumer: create_consumer(KAFKA_CMD_CONFIG.brokers, "event-consumer-group", &["test-evt", "test-evt-subscriber"])
-            }
+    pub fn new(settings: &KafkaSettings, group_id: &str, include_subscriber_stream: bool) -> Self {
+        let consumer = if include_subscriber_stream {
+            create_consumer(&settings.brokers, group_id, &[settings.event_topic.as_str(), settings.subscriber_topic.as_str()])
         }
         else {
-            Self {
-                hooks: Vec::new(),
-                consumer: create_consumer(KAFKA_CMD_CONFIG.brokers, "event-consumer-group", &["test-evt"])
-            }
+            create_consumer(&settings.brokers, group_id, &[settings.event_topic.as_str()])
+        };
+        Self {
+            hooks: Vec::new(),
+            consumer: consumer,
+            event_topic: settings.event_topic.clone(),
+            subscriber_topic: settings.subscriber_topic.clone()
         }
     }
     
@@ -111,7 +92,7 @@ impl<'a> LedgerEventsConsumer<'a> {
                             match some_payload {
                                 Ok(payload) => {
                                     match m.topic() {
-                                        "test-evt" => {
+                                        topic if topic == self.event_topic => {
                                             let the_event : Result<LedgerEvent<Value>, serde_json::Error> = serde_json::from_str(payload);
                                             match the_event { 
                                                 Ok(evt) => {
@@ -129,7 +110,7 @@ impl<'a> LedgerEventsConsumer<'a> {
                                                 }
                                             };
                                         },
-                                        "test-evt-subscriber" => {
+                                        topic if topic == self.subscriber_topic => {
                                             let subscription_event : Result<SubscriptionEvent, serde_json::Error> = serde_json::from_str(payload);
                                             match subscription_event { 
                                                 Ok(sub_evt) => {
@@ -164,16 +145,30 @@ impl<'a> LedgerEventsConsumer<'a> {
     }
 } 
 
-pub fn start_cmd_workers() {
+/// Start `worker.count` workers in the consumer group `worker.name`, each is assigned its own
+/// command partitions. The LMDB store is opened once and shared by the workers, and so are
+/// the shards with `worker.shard_path`, a worker only uses those of its own partitions.
+pub fn start_cmd_workers(settings: &Settings) {
+    let shared_ctx = Arc::new(create_context(&settings.lmdb).expect("Could not open LMDB store"));
+    let shards = if settings.worker.shard_path.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Shards::new(&settings.kafka, &settings.lmdb, &settings.worker)))
+    };
     let mut threads = Vec::new();
-    for worker in KAFKA_CMD_CONFIG.workers {
+    for index in 0..settings.worker.count.max(1) {
+        let worker_settings = settings.clone();
+        let worker_ctx = shared_ctx.clone();
+        let worker_shards = shards.clone().map(|shards| Arc::new(WorkerShards::new(shards)));
         let thread_handle = thread::spawn(move || {
-                println!("Start worker {}", worker.name);
-                let producer = create_producer(KAFKA_EVT_CONFIG.brokers);
-                let consumer = create_consumer(KAFKA_CMD_CONFIG.brokers, worker.name, worker.topics);
-
-                start_process_commands(&producer, &consumer, worker.publish_events_topic.unwrap());
-                println!("Finished worker {}", worker.name);
+                let worker_name = &worker_settings.worker.name;
+                println!("Start worker {} #{}", worker_name, index);
+                let producer = create_producer(&worker_settings.kafka.brokers);
+                let rebalance_listener = worker_shards.clone().map(|shards| shards as Arc<RebalanceListener>);
+                let consumer = create_consumer_with_listener(&worker_settings.kafka.brokers, worker_name, &[worker_settings.kafka.command_topic.as_str()], rebalance_listener);
+
+                start_process_commands(&producer, &consumer, &worker_settings, &worker_ctx, worker_shards.as_ref().map(|shards| &**shards));
+                println!("Finished worker {} #{}", worker_name, index);
             });
         threads.push(thread_handle);
     }
@@ -185,31 +180,211 @@ pub fn start_cmd_workers() {
     }
 }
 
-pub fn send_event(producer: &FutureProducer, events_topic: &str, evt: &LedgerEvent<Value>) {
+/// Write an event and wait for the brokers to acknowledge it, returns whether it was written
+pub fn send_event(producer: &FutureProducer, events_topic: &str, evt: &LedgerEvent<Value>) -> bool {
     match evt.sys.first_published_at() {
         Some(d) => println!("==========> Parsed datetime {}", d),
         None => {println!("==========> None")}
     }
     match serde_json::to_string(&evt) {
         Result::Ok(val) => {
-            producer.send(
+            let delivery = producer.send(
                 FutureRecord::to(events_topic)
                     .payload(&val) 
-                    .key(evt.event_id),
+                    // the events of a document stay in order for the consumers
+                    .key(evt.sys.id),
                 5000
             );
-            
+            match delivery.wait() {
+                Ok(Ok(_)) => true,
+                Ok(Err((err, _))) => {
+                    error!("Could not write event event_id={} err={}", evt.event_id, err);
+                    false
+                },
+                Err(_) => {
+                    error!("Event delivery canceled event_id={}", evt.event_id);
+                    false
+                }
+            }
         }
         Result::Err(err) => {
             print!("called `Result::unwrap()` on an `Err` value: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Map a failed document lookup to a rejection, an unknown id is not a store failure
+fn lookup_error(err: MdbError, message: &str) -> CommandError {
+    match err {
+        MdbError::NotFound => CommandError::new(ErrorCode::NotFound, "Document not found"),
+        _ => CommandError::new(ErrorCode::StoreError, message)
+    }
+}
+
+/// Reject the command when the policy does not allow its user the action on the document.
+/// Unknown documents are rejected as not found and a failing store denies, it never lets a command through.
+fn authorize(policy: Option<&Policy>, lmdb_ctx: &LmdbContext, cmd: &LedgerCommand<Value>) -> Result<(), CommandError> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return Ok(())
+    };
+    let user_id = cmd.user_id.unwrap_or("");
+    let action = cmd.action.name();
+    let allowed = match cmd.action {
+        Action::CREATE{content_type, bucket, env, ..} => policy.allows(user_id, &cmd.roles, action, &Scope{bucket, env, content_type}),
+        _ => match cmd.action.revision().map(|revision| lmdb_ctx.get_latest(revision.id)) {
+            Some(Ok(Some(latest))) => policy.allows(user_id, &cmd.roles, action, &Scope {
+                bucket: latest.sys.bucket,
+                env: latest.sys.env,
+                content_type: latest.sys.content_type
+            }),
+            Some(Ok(None)) | None => true,
+            Some(Err(err)) => return Err(lookup_error(err, "Could not read the document to authorize"))
         }
     };
+    if allowed {
+        Ok(())
+    } else {
+        info!("Denied {} to user_id={}", action, user_id);
+        Err(CommandError::new(ErrorCode::Forbidden, &format!("{} is not allowed for this user", action)))
+    }
+}
+
+/// Replace the patch of an UPDATE with the full payload it gives on the latest version,
+/// so the checks and the event see the whole document
+fn apply_patch(lmdb_ctx: &LmdbContext, cmd: &mut LedgerCommand<Value>) -> Result<(), CommandError> {
+    let patch = match cmd.patch {
+        Some(ref patch) => patch,
+        None => return Ok(())
+    };
+    let revision = match cmd.action {
+        Action::UPDATE(ref revision) => revision,
+        _ => return Err(CommandError::new(ErrorCode::InvalidCommand, "Only an UPDATE can carry a patch"))
+    };
+    if cmd.payload.is_some() {
+        return Err(CommandError::new(ErrorCode::InvalidCommand, "Send either a payload or a patch"));
+    }
+    let mut payload = match lmdb_ctx.get_latest(revision.id) {
+        Ok(Some(latest)) => latest.payload.unwrap_or(Value::Null),
+        Ok(None) => return Err(CommandError::new(ErrorCode::NotFound, "Error cannot update unexisting value")),
+        Err(err) => return Err(lookup_error(err, "Error could not look up previous version"))
+    };
+    match patch {
+        PayloadPatch::JsonPatch(operations) => {
+            let parsed_patch = serde_json::from_value::<json_patch::Patch>(operations.clone())
+                .map_err(|err| CommandError::new(ErrorCode::InvalidCommand, &format!("Invalid JSON patch: {}", err)))?;
+            json_patch::patch(&mut payload, &parsed_patch)
+                .map_err(|err| CommandError::new(ErrorCode::InvalidCommand, &format!("JSON patch does not apply: {}", err)))?;
+        },
+        PayloadPatch::MergePatch(merge_patch) => json_patch::merge(&mut payload, merge_patch)
+    }
+    cmd.payload = Some(payload);
+    Ok(())
+}
+
+/// Look up the document a command applies to and check the command against it with
+/// `domain::state`. Returns the latest version, `None` for CREATE, and the state after the command.
+fn check_transition<'l>(lmdb_ctx: &'l LmdbContext, cmd: &LedgerCommand<Value>) -> Result<(Option<LedgerEvent<'l, Value>>, DocumentState), CommandError> {
+    let revision = match cmd.action.revision() {
+        Some(revision) => revision,
+        None => return transition(None, &cmd.action).map(|state| (None, state))
+    };
+    match lmdb_ctx.get_latest(revision.id) {
+        Ok(Some(latest)) => check_revision(&latest, &cmd.action).map(|state| (Some(latest), state)),
+        Ok(None) => Err(CommandError::new(ErrorCode::StoreError, "Stored document could not be read")),
+        Err(err) => {
+            println!("Error could not look up previous version err={}", err);
+            Err(lookup_error(err, "Error could not look up previous version"))
+        }
+    }
+}
 
+/// Set the payload of a REVERT to the payload of the version it reverts to, which has to be
+/// in the history of the document, so the checks and the event see it like an update
+fn restore_payload(lmdb_ctx: &LmdbContext, cmd: &mut LedgerCommand<Value>) -> Result<(), CommandError> {
+    let (id, to_version) = match cmd.action {
+        Action::REVERT{id, to_version, ..} => (id, to_version),
+        _ => return Ok(())
+    };
+    if cmd.payload.is_some() {
+        return Err(CommandError::new(ErrorCode::InvalidCommand, "A REVERT takes the payload of the version it reverts to"));
+    }
+    match lmdb_ctx.get_version(to_version) {
+        Ok(Some(ref evt)) if evt.sys.id != id =>
+            Err(CommandError::new(ErrorCode::NotFound, "Version to revert to is not in the history of the document")),
+        Ok(Some(LedgerEvent{payload: Some(payload), ..})) => {
+            cmd.payload = Some(payload);
+            Ok(())
+        },
+        Ok(Some(_)) => Err(CommandError::new(ErrorCode::InvalidCommand, "Cannot revert to a deleted version")),
+        Ok(None) | Err(MdbError::NotFound) =>
+            Err(CommandError::new(ErrorCode::NotFound, "Version to revert to is not in the history of the document")),
+        Err(_) => Err(CommandError::new(ErrorCode::StoreError, "Error could not read the version to revert to"))
+    }
+}
+
+/// Validate a payload against the schema of its content type, without a schema any payload is
+/// accepted. The payload of a content type document must itself be a valid schema.
+/// Returns the version of the schema the payload was validated against.
+fn validate_payload(schema_ctx: &LmdbContext, category: &str, bucket: &str, env: &str, content_type: &str, payload: &Option<Value>) -> Result<Option<u32>, CommandError> {
+    if category == SCHEMA_CATEGORY {
+        return match payload.as_ref().map(|schema| serde_json::from_value::<ContentTypeSchema>(schema.clone())) {
+            Some(Ok(_)) => Ok(None),
+            Some(Err(err)) => Err(CommandError::new(ErrorCode::InvalidCommand, &format!("Invalid content type schema: {}", err))),
+            None => Err(CommandError::new(ErrorCode::InvalidCommand, "Content type document without schema"))
+        };
+    }
+    match schema_ctx.get_schema(bucket, env, content_type) {
+        Ok(Some(schema)) => {
+            let violations = schema.validate(payload);
+            if violations.is_empty() {
+                Ok(Some(schema.version))
+            } else {
+                info!("Payload does not match content type {}, {} violations", content_type, violations.len());
+                Err(CommandError::with_violations(ErrorCode::SchemaViolation, &format!("Payload does not match content type {}", content_type), violations))
+            }
+        },
+        Ok(None) => Ok(None),
+        Err(err) => {
+            error!("Error while reading schema of content type {} err={}", content_type, err);
+            Err(CommandError::new(ErrorCode::StoreError, "Could not read the schema of the content type"))
+        }
+    }
 }
 
-pub fn start_process_commands(producer: &FutureProducer, consumer: &LoggingConsumer, publish_events_topic: &str) {
+/// Validate the payload of the commands that set one, returns the schema version to record.
+/// Schemas are read from `schema_ctx`, a shard only has the documents of its partition.
+fn validate_command(lmdb_ctx: &LmdbContext, schema_ctx: &LmdbContext, cmd: &LedgerCommand<Value>) -> Result<Option<u32>, CommandError> {
+    match cmd.action {
+        Action::CREATE{category, content_type, bucket, env} =>
+            validate_payload(schema_ctx, category, bucket, env, content_type, &cmd.payload),
+        Action::UPDATE(_) | Action::COPY(_) | Action::REVERT{..} => match cmd.action.revision().map(|revision| lmdb_ctx.get_latest(revision.id)) {
+            Some(Ok(Some(latest))) => validate_payload(schema_ctx, latest.sys.category, latest.sys.bucket, latest.sys.env, latest.sys.content_type, &cmd.payload),
+            // unknown documents are rejected by the command itself
+            _ => Ok(None)
+        },
+        _ => Ok(None)
+    }
+}
 
-    let lmdb_ctx = create_context().unwrap();
+/// Process commands until the stream ends or an event can not be written. The offset of a
+/// command is only stored once its event is written, so commands are processed at least once:
+/// a crash after writing an event but before recording the result repeats the event when the
+/// command is redelivered. Recorded commands get their first result, see `lmdb_store::commands`.
+/// Exactly-once processing needs a transactional producer that commits the consumer offset
+/// with the event, which rdkafka 0.21 does not have.
+///
+/// `shared_ctx` has the schemas, and the documents when the worker has no shards.
+pub fn start_process_commands(producer: &FutureProducer, consumer: &LoggingConsumer, settings: &Settings, shared_ctx: &LmdbContext, shards: Option<&WorkerShards>) {
+
+    let policy = Policy::load(&settings.policy).unwrap_or_else(|err| panic!("Could not load policy: {}", err));
+    if policy.is_none() {
+        warn!("No policy file configured, every command is allowed");
+    }
+    let publish_events_topic = &settings.kafka.event_topic[..];
+    let publish_results_topic = &settings.kafka.result_topic[..];
+    let dead_letter_topic = &settings.kafka.dead_letter_topic[..];
 
     for message in consumer.start().wait() {
         println!("process command: start");
@@ -223,211 +398,146 @@ pub fn start_process_commands(producer: &FutureProducer, consumer: &LoggingConsu
             }
             Ok(Ok(m)) => {
                 println!("process command: ok");
+                let shard = match shards.map(|shards| shards.shard(m.partition())) {
+                    Some(Ok(Some(shard))) => Some(shard),
+                    // the offset is not stored, the new owner of the partition processes the command
+                    Some(Ok(None)) => {
+                        info!("Skipping command of revoked partition {} offset {}", m.partition(), m.offset());
+                        continue;
+                    },
+                    Some(Err(err)) => {
+                        error!("Shard of partition {} is not available, stopping so the command is redelivered: {}", m.partition(), err);
+                        return;
+                    },
+                    None => None
+                };
+                let lmdb_ctx : &LmdbContext = match shard {
+                    Some(ref shard) => shard,
+                    None => shared_ctx
+                };
                 let gen_content_id = &Uuid::new_v4().to_hyphenated().to_string()[..];
                 let new_version_id = &Uuid::new_v4().to_hyphenated().to_string()[..];
 
                 // parse command
-                let command: Option<(LedgerCommand<Value>, String)> =  match m.payload_view::<str>() {
+                let command: Result<LedgerCommand<Value>, String> =  match m.payload_view::<str>() {
                     Some(some_payload) => {
                         match some_payload {
                             Ok(payload) => {
-                                let mut hasher = Sha256::new();
-                                hasher.input_str("&payload");
-                                let digest = hasher.result_str();
-
                                 match serde_json::from_str(payload) {
-                                    Ok(cmd) => Some((cmd, digest)),
+                                    Ok(cmd) => Ok(cmd),
                                     Err(err) => {
                                         print!("Error while parsing command cmd={} err={}", payload, err);
-                                        None
+                                        Err(format!("Error while parsing command: {}", err))
                                     }
                                 }
                             },
                             Err(err) => {
                                 print!("Error while reading payload err={}", err);
-                                None
+                                Err(format!("Error while reading payload: {}", err))
                             }
                         }
                     },
-                    None => None
+                    None => Err(String::from("Command without payload"))
+                };
+
+                // a document created by the command of another partition reaches the shard through
+                // the event partition, which the shard only reads again when it has to
+                if let (&Some(ref shard), &Ok(ref cmd)) = (&shard, &command) {
+                    if let Some(revision) = cmd.action.revision() {
+                        if let Err(MdbError::NotFound) = shard.get(revision.id) {
+                            if let Err(err) = shard.catch_up() {
+                                error!("Shard of partition {} could not catch up, stopping so the command is redelivered: {}", m.partition(), err);
+                                return;
+                            }
+                        }
+                    }
+                }
+
+                // a command delivered again, e.g. after a rebalance, gets the outcome it had the first time.
+                // The result of a CREATE is recorded with its document, which can be in the shard of
+                // another partition, the shared store of the event consumer has them all.
+                let previous_result = match command {
+                    Ok(ref cmd) if !cmd.tracking_id.is_empty() => match lmdb_ctx.get_command_result(cmd.tracking_id)
+                        .and_then(|found| match found {
+                            None if shard.is_some() => shared_ctx.get_command_result(cmd.tracking_id),
+                            found => Ok(found)
+                        }) {
+                        Ok(previous_result) => previous_result,
+                        Err(err) => {
+                            warn!("Could not look up result of tracking_id={} err={}", cmd.tracking_id, err);
+                            None
+                        }
+                    },
+                    _ => None
                 };
 
                 // create and send event
-                let create_event : Result<&str, Error> = match command {
-                    Some((cmd, digest)) => {
+                match command {
+                    Ok(ref cmd) if previous_result.is_some() => {
+                        info!("Duplicate command tracking_id={}, resending its result", cmd.tracking_id);
+                        let result_json = previous_result.unwrap();
+                        match serde_json::from_str::<CommandResult>(&result_json) {
+                            Ok(result) => if !produce_command_result(&producer, publish_results_topic, &result) {
+                                warn!("Result of tracking_id={} could not be written, the sender will not get it", cmd.tracking_id);
+                            },
+                            Err(err) => error!("Error while parsing stored result={} err={}", result_json, err)
+                        }
+                        if let Err(e) = consumer.store_offset(&m) {
+                            warn!("Error while storing offset: {} for tracking_id {}", e, cmd.tracking_id);
+                        }
+                    },
+                    Ok(mut cmd) => {
+                        // the policy comes first, a denied user learns nothing about the document.
+                        // From here on the payload of a patch or revert command is the resulting document
+                        let prepared = authorize(policy.as_ref(), lmdb_ctx, &cmd)
+                            .and_then(|_| apply_patch(lmdb_ctx, &mut cmd))
+                            .and_then(|_| restore_payload(lmdb_ctx, &mut cmd));
+                        let digest = payload_digest(&cmd.payload);
                         // Serialize it to a JSON string.
                         let now_utc_str = &Utc::now().to_rfc3339()[..];
                         let new_event_id = &Uuid::new_v4().to_hyphenated().to_string()[..];
+                        let tracking_id = cmd.tracking_id;
+                        let event_tracking_id = if tracking_id.is_empty() { None } else { Some(tracking_id) };
                         let user_str = match cmd.user_id {
                             Some(user) => Box::new(user),
                             None => Box::new("") // TODO: decide how to do
                         };
                                             
-                        match cmd.action {
-                            Action::CREATE{category, content_type, bucket, env} => {
-                                info!("CREATE category={} content_type={} bucket={} env={}", category, content_type, bucket, env);
-
-                                match lmdb_ctx.get(&gen_content_id) {
-                                    Ok(latest_version) => {
-                                        info!("Tried to create a new value with an already existing id or version {}", latest_version);
-                                        Err(Error::new(ErrorKind::Other, "Tried to create a new value with an already existing id or version"))
-                                    },
-                                    Err(err) => match err {
-                                        MdbError::NotFound => {
-                                            let evt = LedgerEvent {
-                                                sys: Sys {
-                                                    id: &gen_content_id,
-                                                    env: &env,
-                                                    category: &category,
-                                                    content_type: &content_type,
-                                                    bucket: &bucket,
-                                                    version: &new_version_id,
-                                                    created_by: &user_str,
-                                                    created_at: Some(now_utc_str),
-                                                    updated_by: &user_str,
-                                                    updated_at: Some(now_utc_str),
-                                                    first_published_at: Some(now_utc_str),
-                                                    published_at: None,
-                                                    published_by: None,
-                                                    sealed_at: None,
-                                                    sealed_by: None,
-                                                    previous_version: None,
-                                                    published_version: None,
-                                                    published_count: 0,
-                                                    payload_checksum: Some(&digest)
-                                                },
-                                                event_id: new_event_id,
-                                                action: Action::CREATE{category, content_type, bucket, env},
-                                                payload: cmd.payload,
-                                                
-                                            };
-                                            send_event(&producer, publish_events_topic, &evt);
-                                            Result::Ok(&gen_content_id)
-                                        },
-                                        _ => Err(Error::new(ErrorKind::Other, "Could not verify that the id is new"))
-
-                                    }
-                                }
-                            },
-                            Action::UPDATE(revision) => {
-                                info!("UPDATE id={} version={}", revision.id, revision.version);
-                                match lmdb_ctx.get_latest(&revision.id) {
-                                    Ok(l) => match l {
-                                        Some(latest_value) => {
-                                            if latest_value.sys.version != revision.version {
-                                                println!("Optimistic lock error");
-                                                Err(Error::new(ErrorKind::Other, "Optimistic lock error, retry change on latest data"))
-                                            }
-                                            else if latest_value.sys.id != revision.id {
-                                                println!("Id mismatch");
-                                                Err(Error::new(ErrorKind::Other,"internal id mismatch"))
-
-                                            }
-                                            else if latest_value.sys.sealed_at != None {
-                                                println!("Document with id={} is sealed at {} by {}", latest_value.sys.id, latest_value.sys.sealed_at.unwrap(), latest_value.sys.sealed_by.unwrap());
-                                                Err(Error::new(ErrorKind::Other, "Document is sealed. Copy data to new document."))
-                                            }
-                                            else {
-                                                let evt = LedgerEvent {
-                                                    sys: Sys {
-                                                        id: &revision.id,
-                                                        version: &new_version_id,
-                                                        updated_by: &user_str,
-                                                        updated_at: Some(now_utc_str),
-                                                        previous_version: Some(&revision.version),
-                                                        payload_checksum: Some(&digest),
-                                                        ..latest_value.sys
-                                                    },
-                                                    event_id: new_event_id,
-                                                    action: Action::UPDATE(revision),
-                                                    payload: cmd.payload,
-                                                    
-                                                };
-                                                send_event(&producer, publish_events_topic, &evt);
-                                                Result::Ok(&new_version_id)
-                                            }
-                                        },
-                                        None => {
-                                            println!("Error cannot update unexisting value");
-                                            Err(Error::new(ErrorKind::Other,"Error cannot update unexisting value"))
-                                        }
-                                    },
-                                    Err(err) => {
-                                        println!("Error could not look up previous version");
-                                         Err(Error::new(ErrorKind::Other, "Error could not look up previous version"))
-                                    }
-                                }
-                            },
-                            Action::DELETE(revision) => {
-                                info!("DELETE id={} version={}", revision.id, revision.version);
-                                match lmdb_ctx.get_latest(&revision.id) {
-                                    Ok(l) => match l {
-                                        Some(latestValue) => {
-                                            if latestValue.sys.version != revision.version {
-                                                println!("Optimistic lock error")
-                                            }
-                                            if latestValue.sys.id != revision.id {
-                                                println!("Id mismatch")
-                                            }
-
-                                            let evt = LedgerEvent {
-                                                sys: Sys {
-                                                    id: &revision.id,
-                                                    version: &new_version_id,
-                                                    updated_by: &user_str,
-                                                    updated_at: Some(now_utc_str),
-                                                    previous_version: Some(&revision.version),
-                                                    payload_checksum: Some(&digest),
-                                                    ..latestValue.sys
-                                                },
-                                                event_id: new_event_id,
-                                                action: Action::DELETE(revision),
-                                                payload: None,
-                                                
-                                            };
-                                            send_event(&producer, publish_events_topic, &evt);
-                                            Result::Ok(&new_version_id)
+                        // check the state of the document and the payload before anything is emitted
+                        let checked = prepared
+                            .and_then(|_| validate_command(lmdb_ctx, shared_ctx, &cmd))
+                            .and_then(|content_type_version| check_transition(lmdb_ctx, &cmd)
+                                .map(|(latest, state)| (content_type_version, latest, state)));
+                        // digests of the content kept by lifecycle actions and of a deleted document,
+                        // the events borrow them so they have to outlive the match
+                        let latest_digest = match checked {
+                            Ok((_, Some(ref latest), _)) => payload_digest(&latest.payload),
+                            _ => String::new()
+                        };
+                        let deleted_digest = payload_digest(&None);
+                        let create_event : Result<LedgerEvent<Value>, CommandError> = match checked {
+                            Err(denied) => Err(denied),
+                            Ok((content_type_version, latest, state)) => match (cmd.action, latest) {
+                                (Action::CREATE{category, content_type, bucket, env}, _) => {
+                                    info!("CREATE category={} content_type={} bucket={} env={}", category, content_type, bucket, env);
+
+                                    match lmdb_ctx.get(&gen_content_id) {
+                                        Ok(latest_version) => {
+                                            info!("Tried to create a new value with an already existing id or version {}", latest_version);
+                                            Err(CommandError::new(ErrorCode::AlreadyExists, "Tried to create a new value with an already existing id or version"))
                                         },
-                                        None => {
-                                            println!("Error cannot update unexisting value");
-                                            Err(Error::new(ErrorKind::Other,"Error cannot update unexisting value"))
-                                        }
-                                    },
-                                    Err(err) => {
-                                        println!("Error could not look up previous version err={}", err);
-                                         Err(Error::new(ErrorKind::Other, "Error could not look up previous version"))
-                                    }
-                                }
-                            },
-                            Action::COPY(revision) => {
-                                info!("COPY id={} version={}", revision.id, revision.version);
-
-                                match lmdb_ctx.get_latest(&gen_content_id) {
-                                     Ok(l) => match l {
-                                        Some(latest_value) => {
-                                            if latest_value.sys.version != revision.version {
-                                                println!("Optimistic lock error");
-                                                Err(Error::new(ErrorKind::Other, "Optimistic lock error, retry change on latest data"))
-                                            }
-                                            else if latest_value.sys.id != revision.id {
-                                                println!("Id mismatch");
-                                                Err(Error::new(ErrorKind::Other,"internal id mismatch"))
-
-                                            }
-                                            else if latest_value.sys.sealed_at != None {
-                                                println!("Document with id={} is sealed at {} by {}", latest_value.sys.id, latest_value.sys.sealed_at.unwrap(), latest_value.sys.sealed_by.unwrap());
-                                                Err(Error::new(ErrorKind::Other, "Document is sealed. Copy data to new document."))
-                                            }
-                                            
-                                            else if latest_value.sys.payload_checksum != Some(&digest) {
-                                                println!("Invalid copy (unallowed update) command of id={}", latest_value.sys.id);
-                                                Err(Error::new(ErrorKind::Other, "Invalid copy (unallowed update) command"))
-                                            }
-                                            else { 
+                                        Err(err) => match err {
+                                            MdbError::NotFound => {
                                                 let evt = LedgerEvent {
                                                     sys: Sys {
                                                         id: &gen_content_id,
+                                                        env: &env,
+                                                        category: &category,
+                                                        content_type: &content_type,
+                                                        bucket: &bucket,
                                                         version: &new_version_id,
+                                                        created_by: &user_str,
+                                                        created_at: Some(now_utc_str),
                                                         updated_by: &user_str,
                                                         updated_at: Some(now_utc_str),
                                                         first_published_at: None,
@@ -435,102 +545,333 @@ pub fn start_process_commands(producer: &FutureProducer, consumer: &LoggingConsu
                                                         published_by: None,
                                                         sealed_at: None,
                                                         sealed_by: None,
-                                                        previous_version: Some(latest_value.sys.version),
+                                                        previous_version: None,
                                                         published_version: None,
                                                         published_count: 0,
                                                         payload_checksum: Some(&digest),
-                                                        ..latest_value.sys
+                                                        previous_hash: None,
+                                                        content_type_version: content_type_version,
+                                                        state: Some(state)
                                                     },
                                                     event_id: new_event_id,
-                                                    action: Action::COPY(revision),
+                                                    action: Action::CREATE{category, content_type, bucket, env},
                                                     payload: cmd.payload,
-                                                };    
-                                                send_event(&producer, publish_events_topic, &evt);
-                                                Result::Ok(&gen_content_id)
-                                                }
-                                        },
-                                        None => {
-                                            info!("Error cannot update unexisting value id={} version={}", revision.id, revision.version);
-                                            Err(Error::new(ErrorKind::Other,"Error cannot update unexisting value"))
+                                                    patch: None,
+                                                    hash: None,
+                                                    tracking_id: event_tracking_id
+                                                }.chained();
+                                                Ok(evt)
+                                            },
+                                            _ => Err(CommandError::new(ErrorCode::StoreError, "Could not verify that the id is new"))
+
                                         }
-                                    },
-                                    Err(err) => {
-                                         info!("Tried to copy an non existing document id={} version={}", revision.id, revision.version);
-                                         Err(Error::new(ErrorKind::Other, "Tried to copy an non existing document"))
                                     }
-                                }
-                            },
-                            Action::SEAL(revision) => {
-                                info!("SEAL id={} version={}", revision.id, revision.version);
-                                match lmdb_ctx.get_latest(&revision.id) {
-                                    Ok(l) => match l {
-                                        Some(latest_value) => {
-                                            if latest_value.sys.version != revision.version {
-                                                info!("Optimistic lock error");
-                                                Err(Error::new(ErrorKind::Other, "Optimistic lock error, retry change on latest data"))
-                                            }
-                                            else if latest_value.sys.id != revision.id {
-                                                info!("Id mismatch");
-                                                Err(Error::new(ErrorKind::Other,"internal id mismatch"))
-
-                                            }
-                                            else if latest_value.sys.sealed_at != None {
-                                                info!("Document with id={} is sealed at {} by {}", latest_value.sys.id, latest_value.sys.sealed_at.unwrap(), latest_value.sys.sealed_by.unwrap());
-                                                Err(Error::new(ErrorKind::Other, "Document is sealed. Copy data to new document."))
-                                            }
-                                            else {
-                                                let evt = LedgerEvent {
-                                                    sys: Sys {
-                                                        id: &revision.id,
-                                                        version: &new_version_id,
-                                                        updated_by: &user_str,
-                                                        updated_at: Some(now_utc_str),
-                                                        sealed_by: Some(&user_str),
-                                                        sealed_at: Some(now_utc_str),
-                                                        previous_version: Some(&revision.version),
-                                                        payload_checksum: Some(&digest),
-                                                        ..latest_value.sys
-                                                    },
-                                                    event_id: new_event_id,
-                                                    action: Action::SEAL(revision),
-                                                    payload: cmd.payload,
-                                                    
-                                                };
-                                                send_event(&producer, publish_events_topic, &evt);
-                                                Result::Ok(&new_version_id)
-                                            }
+                                },
+                                (Action::UPDATE(revision), Some(latest_value)) => {
+                                    info!("UPDATE id={} version={}", revision.id, revision.version);
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            content_type_version: content_type_version,
+                                            state: Some(state),
+                                            ..latest_value.sys
                                         },
-                                        None => {
-                                            println!("Error cannot update unexisting value");
-                                            Err(Error::new(ErrorKind::Other,"Error cannot update unexisting value"))
-                                        }
-                                    },
-                                    Err(err) => {
-                                        println!("Error could not look up previous version err={}", err);
-                                         Err(Error::new(ErrorKind::Other, "Error could not look up previous version"))
+                                        event_id: new_event_id,
+                                        action: Action::UPDATE(revision),
+                                        payload: cmd.payload,
+                                        patch: cmd.patch,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::DELETE(revision), Some(latest_value)) => {
+                                    info!("DELETE id={} version={}", revision.id, revision.version);
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&deleted_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::DELETE(revision),
+                                        payload: None,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::COPY(revision), Some(latest_value)) => {
+                                    info!("COPY id={} version={}", revision.id, revision.version);
+                                    if latest_value.sys.payload_checksum != Some(&digest) {
+                                        println!("Invalid copy (unallowed update) command of id={}", latest_value.sys.id);
+                                        Err(CommandError::new(ErrorCode::InvalidCommand, "Invalid copy (unallowed update) command"))
                                     }
+                                    else {
+                                        let evt = LedgerEvent {
+                                            sys: Sys {
+                                                id: &gen_content_id,
+                                                version: &new_version_id,
+                                                updated_by: &user_str,
+                                                updated_at: Some(now_utc_str),
+                                                first_published_at: None,
+                                                published_at: None,
+                                                published_by: None,
+                                                sealed_at: None,
+                                                sealed_by: None,
+                                                previous_version: Some(latest_value.sys.version),
+                                                published_version: None,
+                                                published_count: 0,
+                                                payload_checksum: Some(&digest),
+                                                previous_hash: latest_value.hash.clone(),
+                                                content_type_version: content_type_version,
+                                                state: Some(state),
+                                                ..latest_value.sys
+                                            },
+                                            event_id: new_event_id,
+                                            action: Action::COPY(revision),
+                                            payload: cmd.payload,
+                                            patch: None,
+                                            hash: None,
+                                            tracking_id: event_tracking_id
+                                        }.chained();
+                                        Ok(evt)
+                                    }
+                                },
+                                (Action::SEAL(revision), Some(latest_value)) => {
+                                    info!("SEAL id={} version={}", revision.id, revision.version);
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            sealed_by: Some(&user_str),
+                                            sealed_at: Some(now_utc_str),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&latest_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::SEAL(revision),
+                                        // sealing freezes the content as it is
+                                        payload: latest_value.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::PUBLISH(revision), Some(latest_value)) => {
+                                    info!("PUBLISH id={} version={}", revision.id, revision.version);
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            published_count: latest_value.sys.published_count + 1,
+                                            // the draft the editor approved, not the PUBLISH event itself
+                                            published_version: Some(&revision.version),
+                                            published_at: Some(now_utc_str),
+                                            published_by: Some(&user_str),
+                                            first_published_at: latest_value.sys.first_published_at.or(Some(now_utc_str)),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&latest_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::PUBLISH(revision),
+                                        // publishing does not change the content
+                                        payload: latest_value.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::UNPUBLISH(revision), Some(latest_value)) => {
+                                    info!("UNPUBLISH id={} version={}", revision.id, revision.version);
+                                    // keep published_count and first_published_at as history
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            published_version: None,
+                                            published_at: None,
+                                            published_by: None,
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&latest_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::UNPUBLISH(revision),
+                                        payload: latest_value.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::REVERT{id, version, to_version}, Some(latest_value)) => {
+                                    info!("REVERT id={} version={} to_version={}", id, version, to_version);
+                                    // the action records the version the payload was restored from
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            previous_version: Some(&version),
+                                            payload_checksum: Some(&digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            content_type_version: content_type_version,
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::REVERT{id, version, to_version},
+                                        payload: cmd.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::ARCHIVE(revision), Some(latest_value)) => {
+                                    info!("ARCHIVE id={} version={}", revision.id, revision.version);
+                                    // archiving keeps the content, only the state changes
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&latest_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::ARCHIVE(revision),
+                                        payload: latest_value.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                (Action::UNARCHIVE(revision), Some(latest_value)) => {
+                                    info!("UNARCHIVE id={} version={}", revision.id, revision.version);
+                                    // archived documents are never published, so they come back as draft
+                                    let evt = LedgerEvent {
+                                        sys: Sys {
+                                            id: &revision.id,
+                                            version: &new_version_id,
+                                            updated_by: &user_str,
+                                            updated_at: Some(now_utc_str),
+                                            previous_version: Some(&revision.version),
+                                            payload_checksum: Some(&latest_digest),
+                                            previous_hash: latest_value.hash.clone(),
+                                            state: Some(state),
+                                            ..latest_value.sys
+                                        },
+                                        event_id: new_event_id,
+                                        action: Action::UNARCHIVE(revision),
+                                        payload: latest_value.payload,
+                                        patch: None,
+                                        hash: None,
+                                        tracking_id: event_tracking_id
+                                    }.chained();
+                                    Ok(evt)
+                                },
+                                // check_transition only passes revisions of existing documents
+                                (_, None) => Err(CommandError::new(ErrorCode::NotFound, "Document not found"))
+                            }
+                        };
+
+                        // the command is only acknowledged once its event is written, stop without
+                        // storing the offset so it is delivered again after a restart
+                        if let Ok(ref evt) = create_event {
+                            if !send_event(&producer, publish_events_topic, evt) {
+                                error!("Event of tracking_id={} could not be written, stopping so the command is redelivered", tracking_id);
+                                return;
+                            }
+                            // store it right away, so the next command is checked against it even
+                            // before the event consumer has stored it. A new document can belong to
+                            // another partition, its shard stores it when a command refers to it.
+                            let same_document = evt.action.revision().map_or(false, |revision| revision.id == evt.sys.id);
+                            if shard.is_none() || same_document {
+                                if let Err(err) = lmdb_ctx.set_event(evt) {
+                                    // later commands would be checked against a stale document
+                                    error!("Error while storing event of tracking_id={} err={}, stopping so the command is redelivered", tracking_id, err);
+                                    return;
                                 }
                             }
                         }
-                    },
-                    None => {
-                        print!("Error while reading empty event");
-                        Err(Error::new(ErrorKind::Other, "foo"))
-                    }
-                    
-                    
-                };
 
+                        // report the outcome to the sender of the command
+                        let result = match create_event {
+                            Ok(evt) => {
+                                println!("Finished event id: {} version: {}", evt.sys.id, evt.sys.version);
+                                CommandResult::Accepted{tracking_id, id: evt.sys.id, version: evt.sys.version, event_id: new_event_id}
+                            },
+                            Err(error) => {
+                                info!("Rejected command tracking_id={} error={}", tracking_id, error);
+                                CommandResult::Rejected{tracking_id, error}
+                            }
+                        };
+                        if !produce_command_result(&producer, publish_results_topic, &result) {
+                            warn!("Result of tracking_id={} could not be written, the sender will not get it", tracking_id);
+                        }
+
+                        // a store failure is worth a retry, every other outcome is final
+                        let retryable = match result {
+                            CommandResult::Rejected{ref error, ..} => error.code == ErrorCode::StoreError,
+                            _ => false
+                        };
+                        if !tracking_id.is_empty() && !retryable {
+                            if let Err(err) = lmdb_ctx.set_command_result(&result) {
+                                error!("Error while recording result of tracking_id={} err={}", tracking_id, err);
+                            }
+                        }
 
-                match create_event {
-                    Ok(id) => {
-                        println!("Finished event id: {}", id);
                         if let Err(e) = consumer.store_offset(&m) {
-                            warn!("Error while storing offset: {} for event_id {}", e, id);
+                            warn!("Error while storing offset: {} for tracking_id {}", e, tracking_id);
+                        }
+                    },
+                    Err(parse_error) => {
+                        // park the record on the dead letter topic, it will never parse on retry
+                        let dead_letter = dead_letter_from_message(&m, &parse_error);
+                        if !produce_dead_letter(&producer, dead_letter_topic, &dead_letter) {
+                            error!("Dead letter of offset={} could not be written, stopping so the record is redelivered", m.offset());
+                            return;
+                        }
+                        if let Err(e) = consumer.store_offset(&m) {
+                            warn!("Error while storing offset: {} for dead letter offset {}", e, m.offset());
                         }
-                    }
-                    Err(_) => {
-                        print!("Empty event");
                     }
                 }
 
@@ -538,4 +879,85 @@ pub fn start_process_commands(producer: &FutureProducer, consumer: &LoggingConsu
         }
         println!("process command: end");
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{DocumentState, Revision};
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    fn update<'a>(payload: Option<Value>, patch: PayloadPatch) -> LedgerCommand<'a, Value> {
+        LedgerCommand {
+            tracking_id: "t",
+            action: Action::UPDATE(Revision{id: "doc", version: "v1"}),
+            payload: payload,
+            user_id: Some("user"),
+            roles: vec![],
+            patch: Some(patch)
+        }
+    }
+
+    fn revert<'a>(id: &'a str, to_version: &'a str) -> LedgerCommand<'a, Value> {
+        LedgerCommand {
+            tracking_id: "t",
+            action: Action::REVERT{id: id, version: "v2", to_version: to_version},
+            payload: None,
+            user_id: Some("user"),
+            roles: vec![],
+            patch: None
+        }
+    }
+
+    #[test]
+    fn patches_apply_to_the_latest_payload() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("doc", "v1", DocumentState::Draft, Some(json!({"title": "a", "tags": ["x"]})))).unwrap();
+
+        let mut json_patch = update(None, PayloadPatch::JsonPatch(json!([{"op": "add", "path": "/tags/-", "value": "y"}])));
+        assert!(apply_patch(&lmdb_ctx, &mut json_patch).is_ok());
+        assert_eq!(json_patch.payload, Some(json!({"title": "a", "tags": ["x", "y"]})));
+
+        let mut merge_patch = update(None, PayloadPatch::MergePatch(json!({"title": "b", "tags": null})));
+        assert!(apply_patch(&lmdb_ctx, &mut merge_patch).is_ok());
+        assert_eq!(merge_patch.payload, Some(json!({"title": "b"})));
+    }
+
+    #[test]
+    fn patch_that_does_not_apply_is_invalid() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("doc", "v1", DocumentState::Draft, Some(json!({"title": "a"})))).unwrap();
+
+        let mut failing = update(None, PayloadPatch::JsonPatch(json!([{"op": "remove", "path": "/missing"}])));
+        assert_eq!(apply_patch(&lmdb_ctx, &mut failing).unwrap_err().code, ErrorCode::InvalidCommand);
+        let mut both = update(Some(json!({"title": "c"})), PayloadPatch::MergePatch(json!({})));
+        assert_eq!(apply_patch(&lmdb_ctx, &mut both).unwrap_err().code, ErrorCode::InvalidCommand);
+    }
+
+    #[test]
+    fn revert_takes_the_payload_of_a_version_of_the_document() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("doc", "v1", DocumentState::Draft, Some(json!({"title": "a"})))).unwrap();
+        lmdb_ctx.set_event(&event("other", "o1", DocumentState::Draft, Some(json!({"title": "b"})))).unwrap();
+
+        let mut cmd = revert("doc", "v1");
+        assert!(restore_payload(&lmdb_ctx, &mut cmd).is_ok());
+        assert_eq!(cmd.payload, Some(json!({"title": "a"})));
+
+        let mut foreign = revert("doc", "o1");
+        assert_eq!(restore_payload(&lmdb_ctx, &mut foreign).unwrap_err().code, ErrorCode::NotFound);
+        let mut unknown = revert("doc", "v9");
+        assert_eq!(restore_payload(&lmdb_ctx, &mut unknown).unwrap_err().code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn undelivered_event_is_reported() {
+        let producer : FutureProducer = self::rdkafka::config::ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("message.timeout.ms", "100")
+            .create()
+            .unwrap();
+        assert!(!send_event(&producer, "events", &event("doc", "v1", DocumentState::Draft, None)));
+    }
+}
@@ -6,9 +6,13 @@ extern crate serde_json;
 
 use self::rdkafka::producer::{FutureProducer, FutureRecord};
 use self::rdkafka::config::ClientConfig;
+use futures::Future;
+use futures::future;
 
-use domain::{LedgerCommand, SubscriptionEvent};
+use domain::{LedgerCommand, SubscriptionEvent, CommandResult};
+use auth::Identity;
 use serde_json::Value;
+use self::uuid::Uuid;
 
 
 pub fn create_producer(brokers: &str) -> FutureProducer {
@@ -19,42 +23,77 @@ pub fn create_producer(brokers: &str) -> FutureProducer {
         .expect("Producer creation failed")
 }
 
-
 //pub fn create_producer(brokers: &str) ->
 
-pub fn produce_command(command: LedgerCommand<Value>, user_id: &str) {//tracking_id: &str, payload: &str, key: &str, action: Action) {
-    let producer = create_producer("localhost:9092");
+/// Completes with the tracking id once the brokers acknowledged the command
+pub type CommandDelivery = Box<Future<Item=String, Error=String> + Send>;
+
+/// Send a command to the command topic as the authenticated user, a command without
+/// tracking id gets a new one to correlate the result with
+pub fn produce_command(producer: &FutureProducer, command_topic: &str, command: LedgerCommand<Value>, identity: &Identity) -> CommandDelivery {
+    let generated_tracking_id = Uuid::new_v4().to_hyphenated().to_string();
+    let tracking_id = if command.tracking_id.is_empty() {
+        &generated_tracking_id[..]
+    } else {
+        command.tracking_id
+    };
 
-    
     let cmd = LedgerCommand {
-        tracking_id: "asdfgh",
-        user_id: Some(user_id),
+        tracking_id: tracking_id,
+        user_id: Some(&identity.user_id),
+        roles: identity.roles.clone(),
         ..command
     };
 
     // Serialize it to a JSON string.
     match serde_json::to_string(&cmd) {
         Result::Ok(val) => {
-/*            producer.send_copy::<String, String>(
-                    "test", 
-                    None, 
-                    Some(&val), 
-                    Some(&cmd.tracking_id), 
-                    None, 
-                    1000); */
-            producer.send(
-                FutureRecord::to("test-cmd")
+            let tracking_id = String::from(tracking_id);
+            let delivery = producer.send(
+                FutureRecord::to(command_topic)
                     .payload(&val) 
-                    .key(cmd.tracking_id),
+                    .key(cmd.routing_key()),
                 5000
             );
+            Box::new(delivery.then(move |delivered| match delivered {
+                Ok(Ok(_)) => Ok(tracking_id),
+                Ok(Err((err, _))) => Err(format!("Could not write command tracking_id={}: {}", tracking_id, err)),
+                Err(_) => Err(format!("Command delivery canceled tracking_id={}", tracking_id))
+            }))
+        }
+        Result::Err(err) => Box::new(future::err(format!("Could not serialize command: {}", err)))
+    }
+}
+
+/// Write a command result and wait for the brokers to acknowledge it, returns whether it was written
+pub fn produce_command_result(producer: &FutureProducer, results_topic: &str, result: &CommandResult) -> bool {
+
+    // Serialize it to a JSON string.
+    match serde_json::to_string(result) {
+        Result::Ok(val) => {
+            let delivery = producer.send(
+                FutureRecord::to(results_topic)
+                    .payload(&val) 
+                    .key(result.tracking_id()),
+                5000
+            );
+            match delivery.wait() {
+                Ok(Ok(_)) => true,
+                Ok(Err((err, _))) => {
+                    error!("Could not write result of tracking_id={} err={}", result.tracking_id(), err);
+                    false
+                },
+                Err(_) => {
+                    error!("Result delivery canceled tracking_id={}", result.tracking_id());
+                    false
+                }
+            }
         }
         Result::Err(err) => {
               print!("called `Result::unwrap()` on an `Err` value: {:?}", err);
+              false
         }
-    };
-        
-    
+    }
 }
 /*
 
@@ -85,15 +124,15 @@ sClientAction::Open{conn_id, sender} => {
                     },
 */
 
-pub fn produce_subscription_event(producer: &FutureProducer, subscription_event: SubscriptionEvent) {//tracking_id: &str, payload: &str, key: &str, action: Action) {
+pub fn produce_subscription_event(producer: &FutureProducer, subscriber_topic: &str, subscription_event: SubscriptionEvent) {//tracking_id: &str, payload: &str, key: &str, action: Action) {
 
     // Serialize it to a JSON string.
     match serde_json::to_string(&subscription_event) {
         Result::Ok(val) => {
             producer.send(
-                FutureRecord::to("test-evt-subscriber")
+                FutureRecord::to(subscriber_topic)
                     .payload(&val) 
-                    .key("keytodo"),
+                    .key(subscription_event.conn_id()),
                 5000
             );
         }
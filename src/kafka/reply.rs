@@ -0,0 +1,124 @@
+extern crate rdkafka;
+extern crate uuid;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::rdkafka::Message;
+use self::rdkafka::config::ClientConfig;
+use self::rdkafka::consumer::Consumer;
+use self::rdkafka::consumer::base_consumer::BaseConsumer;
+use self::uuid::Uuid;
+
+use config::KafkaSettings;
+use domain::CommandResult;
+
+/// Called with the JSON of the `CommandResult`, or `None` when no result arrived in time
+pub type ResultCallback = Box<FnOnce(Option<String>) + Send>;
+
+type PendingResults = Arc<Mutex<HashMap<String, (Instant, ResultCallback)>>>;
+
+/// Follows the reply topic and hands each `CommandResult` to whoever registered its tracking id
+pub struct ResultListener {
+    pending: PendingResults
+}
+
+impl ResultListener {
+
+    /// Start following the reply topic from its end in a background thread
+    pub fn start(settings: &KafkaSettings, timeout: Duration) -> ResultListener {
+        let pending : PendingResults = Arc::new(Mutex::new(HashMap::new()));
+
+        // a group of its own, every listener needs to see every result
+        let group_id = format!("result-listener-{}", Uuid::new_v4().to_hyphenated());
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("group.id", &group_id)
+            .set("bootstrap.servers", &settings.brokers)
+            .set("enable.partition.eof", "false")
+            .set("auto.offset.reset", "latest")
+            .set("enable.auto.commit", "false")
+            .create()
+            .expect("Result listener creation failed");
+        consumer.subscribe(&[settings.result_topic.as_str()]).expect("Can't subscribe to result topic");
+
+        let thread_pending = pending.clone();
+        thread::Builder::new()
+            .name("result-listener".to_owned())
+            .spawn(move || {
+                loop {
+                    match consumer.poll(Duration::from_millis(100)) {
+                        None => {},
+                        Some(Err(err)) => warn!("Kafka error while reading results: {}", err),
+                        Some(Ok(m)) => {
+                            if let Some(Ok(payload)) = m.payload_view::<str>() {
+                                match serde_json::from_str::<CommandResult>(payload) {
+                                    Ok(result) => {
+                                        let waiting = thread_pending.lock().unwrap().remove(result.tracking_id());
+                                        if let Some((_, callback)) = waiting {
+                                            callback(Some(String::from(payload)));
+                                        }
+                                    },
+                                    Err(err) => warn!("Error while parsing result={} err={}", payload, err)
+                                }
+                            }
+                        }
+                    }
+
+                    // give up on results that did not arrive in time
+                    let now = Instant::now();
+                    let expired : Vec<ResultCallback> = {
+                        let mut waiting = thread_pending.lock().unwrap();
+                        let expired_ids : Vec<String> = waiting.iter()
+                            .filter(|(_, (registered_at, _))| now.duration_since(*registered_at) > timeout)
+                            .map(|(tracking_id, _)| tracking_id.clone())
+                            .collect();
+                        expired_ids.iter()
+                            .filter_map(|tracking_id| waiting.remove(tracking_id))
+                            .map(|(_, callback)| callback)
+                            .collect()
+                    };
+                    for callback in expired {
+                        callback(None);
+                    }
+                }
+            })
+            .unwrap();
+
+        ResultListener {
+            pending: pending
+        }
+    }
+
+    /// Register before the command is produced so that a fast result is not missed
+    pub fn register<F: FnOnce(Option<String>) + Send + 'static>(&self, tracking_id: &str, callback: F) {
+        self.pending.lock().unwrap().insert(String::from(tracking_id), (Instant::now(), Box::new(callback)));
+    }
+
+    /// Drop the callback of a command that could not be produced, it is not called
+    pub fn unregister(&self, tracking_id: &str) {
+        self.pending.lock().unwrap().remove(tracking_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn callbacks_without_result_are_called_on_timeout() {
+        let settings = KafkaSettings{brokers: String::from("127.0.0.1:1"), ..KafkaSettings::default()};
+        let listener = ResultListener::start(&settings, Duration::from_millis(50));
+        let (sender, receiver) = channel();
+        let dropped_sender = sender.clone();
+        listener.register("t1", move |result| sender.send(("t1", result)).unwrap());
+        listener.register("t2", move |result| dropped_sender.send(("t2", result)).unwrap());
+        listener.unregister("t2");
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), ("t1", None));
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+}
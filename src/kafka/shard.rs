@@ -0,0 +1,251 @@
+extern crate lmdb_rs;
+extern crate rdkafka;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use self::lmdb_rs::core::MdbError;
+use self::rdkafka::Message;
+use self::rdkafka::config::ClientConfig;
+use self::rdkafka::consumer::Consumer;
+use self::rdkafka::consumer::base_consumer::BaseConsumer;
+use self::rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use config::{KafkaSettings, LmdbSettings, WorkerSettings};
+use domain::LedgerEvent;
+use kafka::consumer::RebalanceListener;
+use lmdb_store::{create_context, LmdbContext};
+use serde_json::Value;
+
+// The documents of each command partition are kept in an LMDB of their own, a shard. The
+// shards are opened once per process and shared by all its workers, each worker only uses the
+// shards of the partitions its consumer owns. Events are keyed by document id like the
+// commands, so event partition n holds every event of the documents of command partition n.
+// A shard catches up with its event partition once after the partition is assigned to a
+// worker, later on that worker stores its own events in it. Documents created by the commands
+// of other partitions are picked up by catching up again when a command refers to a document
+// the shard does not know.
+
+const CATCH_UP_TIMEOUT_MS : u64 = 5000;
+
+/// Offset of the last event partition record stored in a shard
+const SHARD_OFFSET_KEY : &str = "shard|offset";
+
+/// The shards of a process, shared by its workers
+pub struct Shards {
+    event_topic: String,
+    lmdb: LmdbSettings,
+    shard_path: String,
+    events: Mutex<BaseConsumer>,
+    /// every shard opened so far, an LMDB env is opened once per process and kept open
+    opened: Mutex<HashMap<i32, Arc<LmdbContext>>>
+}
+
+/// The shards one worker may use, those of the partitions assigned to its consumer
+pub struct WorkerShards {
+    shards: Arc<Shards>,
+    /// owned partitions, true once the shard caught up since the partition was assigned
+    owned: Mutex<HashMap<i32, bool>>,
+    /// held while a command is processed, a revoke waits for it
+    in_flight: Mutex<()>
+}
+
+/// Shard of an owned partition, the partition is not revoked before the guard is dropped
+pub struct ShardGuard<'a> {
+    shards: &'a Shards,
+    partition: i32,
+    ctx: Arc<LmdbContext>,
+    _in_flight: MutexGuard<'a, ()>
+}
+
+impl<'a> Deref for ShardGuard<'a> {
+    type Target = LmdbContext;
+
+    fn deref(&self) -> &LmdbContext {
+        &self.ctx
+    }
+}
+
+impl<'a> ShardGuard<'a> {
+    /// Store the events written to the event partition since the shard last read it
+    pub fn catch_up(&self) -> Result<usize, String> {
+        self.shards.catch_up(self.partition, &self.ctx)
+    }
+}
+
+/// Store a record of the event partition in a shard. Unreadable records are skipped, the
+/// offset is stored either way so they are not read again.
+fn store_event_record(shard: &LmdbContext, partition: i32, offset: i64, payload: Option<&str>) -> Result<(), String> {
+    match payload {
+        Some(payload) => match serde_json::from_str::<LedgerEvent<Value>>(payload) {
+            Ok(evt) => shard.set_event(&evt).map_err(|err| format!("Could not store event {}: {}", evt.event_id, err))?,
+            Err(err) => warn!("Skipping unreadable event partition={} offset={} err={}", partition, offset, err)
+        },
+        None => warn!("Skipping empty event partition={} offset={}", partition, offset)
+    }
+    shard.set(SHARD_OFFSET_KEY, &offset.to_string())
+        .map_err(|err| format!("Could not store offset of shard {}: {}", partition, err))
+}
+
+impl Shards {
+
+    pub fn new(kafka: &KafkaSettings, lmdb: &LmdbSettings, worker: &WorkerSettings) -> Shards {
+        // only assigned partitions are read and nothing is committed, the offsets live in the shards
+        let events : BaseConsumer = ClientConfig::new()
+            .set("group.id", &format!("{}-shards", worker.name))
+            .set("bootstrap.servers", &kafka.brokers)
+            .set("enable.partition.eof", "false")
+            .set("enable.auto.commit", "false")
+            .create()
+            .expect("Shard consumer creation failed");
+
+        Shards {
+            event_topic: kafka.event_topic.clone(),
+            lmdb: lmdb.clone(),
+            shard_path: worker.shard_path.clone(),
+            events: Mutex::new(events),
+            opened: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn open(&self, partition: i32) -> Result<Arc<LmdbContext>, String> {
+        let mut opened = self.opened.lock().unwrap();
+        if let Some(shard) = opened.get(&partition) {
+            return Ok(shard.clone());
+        }
+        let settings = LmdbSettings {
+            path: format!("{}/partition-{}", self.shard_path, partition),
+            ..self.lmdb.clone()
+        };
+        fs::create_dir_all(&settings.path).map_err(|err| format!("Could not create shard {}: {}", settings.path, err))?;
+        let shard = Arc::new(create_context(&settings).ok_or_else(|| format!("Could not open shard {}", settings.path))?);
+        info!("Opened shard of partition {} at {}", partition, settings.path);
+        opened.insert(partition, shard.clone());
+        Ok(shard)
+    }
+
+    fn catch_up(&self, partition: i32, shard: &LmdbContext) -> Result<usize, String> {
+        let events = self.events.lock().unwrap();
+        let timeout = Duration::from_millis(CATCH_UP_TIMEOUT_MS);
+        let (_, high) = events.fetch_watermarks(&self.event_topic, partition, timeout)
+            .map_err(|err| format!("Could not read watermarks of partition {}: {}", partition, err))?;
+        let mut next = match shard.get(SHARD_OFFSET_KEY) {
+            Ok(offset) => offset.parse::<i64>().map_err(|_| format!("Invalid offset {} in shard of partition {}", offset, partition))? + 1,
+            Err(MdbError::NotFound) => 0,
+            Err(err) => return Err(format!("Could not read offset of shard {}: {}", partition, err))
+        };
+        if next >= high {
+            return Ok(0);
+        }
+
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(&self.event_topic, partition, Offset::Offset(next));
+        events.assign(&assignment).map_err(|err| format!("Could not assign partition {}: {}", partition, err))?;
+        let mut count = 0;
+        while next < high {
+            match events.poll(timeout) {
+                None => return Err(format!("Timed out reading partition {} at offset {}", partition, next)),
+                Some(Err(err)) => return Err(format!("Kafka error while reading partition {}: {}", partition, err)),
+                Some(Ok(m)) => {
+                    store_event_record(shard, partition, m.offset(), m.payload_view::<str>().and_then(Result::ok))?;
+                    next = m.offset() + 1;
+                    count += 1;
+                }
+            }
+        }
+        info!("Shard of partition {} caught up with {} events", partition, count);
+        Ok(count)
+    }
+}
+
+impl WorkerShards {
+
+    pub fn new(shards: Arc<Shards>) -> WorkerShards {
+        WorkerShards {
+            shards: shards,
+            owned: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(())
+        }
+    }
+
+    /// Store of a command partition, caught up with its event partition the first time it is
+    /// used after the partition was assigned. `None` when the partition is not owned, e.g. for
+    /// a command read before its partition was revoked.
+    pub fn shard<'a>(&'a self, partition: i32) -> Result<Option<ShardGuard<'a>>, String> {
+        let in_flight = self.in_flight.lock().unwrap();
+        let caught_up = match self.owned.lock().unwrap().get(&partition) {
+            Some(caught_up) => *caught_up,
+            None => return Ok(None)
+        };
+        let shard = ShardGuard {
+            shards: &self.shards,
+            partition: partition,
+            ctx: self.shards.open(partition)?,
+            _in_flight: in_flight
+        };
+        if !caught_up {
+            shard.catch_up()?;
+            self.owned.lock().unwrap().insert(partition, true);
+        }
+        Ok(Some(shard))
+    }
+}
+
+impl RebalanceListener for WorkerShards {
+
+    /// The shards of the partitions catch up before their next command, a previous owner may
+    /// have written events since this worker last had them
+    fn assigned(&self, partitions: &[i32]) {
+        info!("Assigned command partitions {:?}", partitions);
+        *self.owned.lock().unwrap() = partitions.iter().map(|partition| (*partition, false)).collect();
+    }
+
+    /// Give up the partitions once the command in flight is done, the shards stay open
+    fn revoked(&self) {
+        let _in_flight = self.in_flight.lock().unwrap();
+        info!("Releasing shards of revoked command partitions");
+        self.owned.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{Action, CommandResult, DocumentState, Revision};
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    #[test]
+    fn replayed_shard_answers_redelivered_commands() {
+        let mut created = event("doc", "v1", DocumentState::Draft, None);
+        created.tracking_id = Some("t1");
+        let mut updated = event("doc", "v2", DocumentState::Draft, Some(json!({"title": "a"})));
+        updated.action = Action::UPDATE(Revision{id: "doc", version: "v1"});
+        updated.sys.previous_version = Some("v1");
+        updated.tracking_id = Some("t2");
+        let records = vec![
+            serde_json::to_string(&created).unwrap(),
+            String::from("not an event"),
+            serde_json::to_string(&updated).unwrap()
+        ];
+
+        let shard = temp_context();
+        for (offset, record) in records.iter().enumerate() {
+            store_event_record(&shard, 0, offset as i64, Some(record)).unwrap();
+        }
+
+        assert_eq!(shard.get(SHARD_OFFSET_KEY).unwrap(), "2");
+        assert_eq!(shard.get("doc").unwrap(), "v2");
+        let result_json = shard.get_command_result("t2").unwrap().unwrap();
+        match serde_json::from_str::<CommandResult>(&result_json).unwrap() {
+            CommandResult::Accepted{tracking_id, id, version, event_id} =>
+                assert_eq!((tracking_id, id, version, event_id), ("t2", "doc", "v2", "v2")),
+            CommandResult::Rejected{..} => panic!("expected the accepted result")
+        }
+        assert!(shard.get_command_result("t3").unwrap().is_none());
+    }
+}
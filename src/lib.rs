@@ -14,8 +14,12 @@ extern crate pretty_env_logger;
 #[macro_use] extern crate serde_derive;
 extern crate lmdb_rs as lmdb;
 
+pub mod auth;
+pub mod config;
 pub mod server;
 pub mod domain;
 pub mod kafka;
 pub mod lmdb_store;
+pub mod migration;
+pub mod policy;
 pub mod redis_event_store;
\ No newline at end of file
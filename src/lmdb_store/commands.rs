@@ -0,0 +1,105 @@
+extern crate lmdb_rs;
+extern crate serde_json;
+
+use self::lmdb_rs::Database;
+use self::lmdb_rs::core::MdbError;
+
+use domain::{CommandResult, LedgerEvent};
+use lmdb_store::LmdbContext;
+use serde_json::Value;
+
+// the outcome of every processed command is kept by its tracking id, so a command that is
+// delivered again is answered with its first outcome instead of being applied twice
+
+fn command_key(tracking_id: &str) -> String {
+    format!("cmd|{}", tracking_id)
+}
+
+/// Record the command of an event as accepted within the transaction of `db`, so a store
+/// rebuilt from the events still knows it. A command that already has a result keeps it.
+pub fn record_accepted(db: &Database, event: &LedgerEvent<Value>) -> Result<(), MdbError> {
+    let tracking_id = match event.tracking_id {
+        Some(tracking_id) => tracking_id,
+        None => return Ok(())
+    };
+    match db.get::<&str>(&command_key(tracking_id)) {
+        Ok(_) => return Ok(()),
+        Err(MdbError::NotFound) => {},
+        Err(err) => return Err(err)
+    };
+    let result = CommandResult::Accepted {
+        tracking_id: tracking_id,
+        id: event.sys.id,
+        version: event.sys.version,
+        event_id: event.event_id
+    };
+    match serde_json::to_string(&result) {
+        Ok(result_json) => db.set(&command_key(tracking_id), &result_json),
+        Err(err) => {
+            error!("Error while serializing result of tracking_id={} err={}", tracking_id, err);
+            Ok(())
+        }
+    }
+}
+
+impl LmdbContext {
+
+    /// Outcome of an already processed command as stored JSON, `None` when it is new
+    pub fn get_command_result(&self, tracking_id: &str) -> Result<Option<String>, MdbError> {
+        match self.get(&command_key(tracking_id)) {
+            Ok(result_json) => Ok(Some(result_json)),
+            Err(MdbError::NotFound) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn set_command_result(&self, result: &CommandResult) -> Result<(), MdbError> {
+        match serde_json::to_string(result) {
+            Ok(result_json) => self.set(&command_key(result.tracking_id()), &result_json),
+            Err(err) => {
+                error!("Error while serializing result of tracking_id={} err={}", result.tracking_id(), err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{CommandError, DocumentState, ErrorCode};
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    #[test]
+    fn result_is_found_by_tracking_id() {
+        let lmdb_ctx = temp_context();
+        assert_eq!(lmdb_ctx.get_command_result("t1").unwrap(), None);
+
+        let rejected = CommandResult::Rejected{tracking_id: "t1", error: CommandError::new(ErrorCode::NotFound, "Document not found")};
+        lmdb_ctx.set_command_result(&rejected).unwrap();
+        let result_json = lmdb_ctx.get_command_result("t1").unwrap().unwrap();
+        match serde_json::from_str::<CommandResult>(&result_json).unwrap() {
+            CommandResult::Rejected{tracking_id, error} => {
+                assert_eq!(tracking_id, "t1");
+                assert_eq!(error.code, ErrorCode::NotFound);
+            },
+            _ => panic!("expected the rejection")
+        }
+        assert_eq!(lmdb_ctx.get_command_result("t2").unwrap(), None);
+    }
+
+    #[test]
+    fn stored_event_records_its_command_as_accepted() {
+        let lmdb_ctx = temp_context();
+        let mut evt = event("doc", "v1", DocumentState::Draft, None);
+        evt.tracking_id = Some("t1");
+        lmdb_ctx.set_event(&evt).unwrap();
+
+        let result_json = lmdb_ctx.get_command_result("t1").unwrap().unwrap();
+        match serde_json::from_str::<CommandResult>(&result_json).unwrap() {
+            CommandResult::Accepted{id, version, ..} => assert_eq!((id, version), ("doc", "v1")),
+            _ => panic!("expected the acceptance")
+        }
+    }
+}
@@ -0,0 +1,462 @@
+extern crate crypto;
+extern crate chrono;
+extern crate lmdb_rs;
+extern crate serde_json;
+
+use self::crypto::digest::Digest;
+use self::crypto::sha2::Sha256;
+use self::chrono::{DateTime, Duration, Utc};
+use self::lmdb_rs::Database;
+use self::lmdb_rs::core::MdbError;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use domain::hash::event_hash;
+use lmdb_store::LmdbContext;
+
+/// A sealed root over the first `leaf_count` events of a bucket/env, in the order they were stored
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MerkleRoot {
+    pub bucket: String,
+    pub env: String,
+    pub sequence: u64,
+    pub root: String,
+    pub leaf_count: usize,
+    pub sealed_at: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Left,
+    Right
+}
+
+/// Sibling hash on the path from a leaf to the root
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side
+}
+
+/// Proof that the event of `version` is included under `root`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MerkleProof {
+    pub version: String,
+    pub event_hash: String,
+    pub leaf_index: usize,
+    pub root: MerkleRoot,
+    pub path: Vec<ProofStep>
+}
+
+impl MerkleProof {
+    /// Recompute the root from the event hash and the path, needs nothing from the store
+    pub fn verify(&self) -> bool {
+        let mut hash = leaf_hash(&self.event_hash);
+        for step in &self.path {
+            hash = match step.side {
+                Side::Left => node_hash(&step.hash, &hash),
+                Side::Right => node_hash(&hash, &step.hash)
+            };
+        }
+        hash == self.root.root
+    }
+}
+
+fn sha256(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input_str(part);
+    }
+    hasher.result_str()
+}
+
+// leaves and nodes are prefixed differently so a node can never pass as a leaf
+fn leaf_hash(event_hash: &str) -> String {
+    sha256(&["leaf|", event_hash])
+}
+
+fn node_hash(left: &str, right: &str) -> String {
+    sha256(&["node|", left, right])
+}
+
+/// Hash one level of the tree, an odd last node is promoted unchanged
+fn next_level(level: &[String]) -> Vec<String> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => single.clone(),
+            _ => unreachable!()
+        })
+        .collect()
+}
+
+/// Root over the given event hashes, `None` for an empty list
+pub fn merkle_root(event_hashes: &[String]) -> Option<String> {
+    let mut level : Vec<String> = event_hashes.iter().map(|hash| leaf_hash(hash)).collect();
+    if level.is_empty() {
+        return None;
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.pop()
+}
+
+/// Sibling path from the leaf at `index` to the root
+pub fn merkle_path(event_hashes: &[String], index: usize) -> Vec<ProofStep> {
+    let mut path = Vec::new();
+    let mut level : Vec<String> = event_hashes.iter().map(|hash| leaf_hash(hash)).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        if position % 2 == 1 {
+            path.push(ProofStep{hash: level[position - 1].clone(), side: Side::Left});
+        } else if position + 1 < level.len() {
+            path.push(ProofStep{hash: level[position + 1].clone(), side: Side::Right});
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    path
+}
+
+// the leaves of a bucket/env are stored one key per index, so appending one is a constant
+// number of writes however long the log is
+
+const LEAF_COUNT_PREFIX : &str = "leaves|";
+
+/// Number of leaves stored for a bucket/env
+fn leaf_count_key(bucket: &str, env: &str) -> String {
+    format!("{}{}|{}", LEAF_COUNT_PREFIX, bucket, env)
+}
+
+fn leaf_key(bucket: &str, env: &str, index: usize) -> String {
+    format!("leaf|{}|{}|{:020}", bucket, env, index)
+}
+
+/// Index of the leaf of a version
+fn version_leaf_key(version: &str) -> String {
+    format!("leafof|{}", version)
+}
+
+fn root_key(bucket: &str, env: &str) -> String {
+    format!("merkle|{}|{}", bucket, env)
+}
+
+fn sealed_root_key(bucket: &str, env: &str, sequence: u64) -> String {
+    format!("{}|{}", root_key(bucket, env), sequence)
+}
+
+fn parse_index(value: &str) -> Result<usize, MdbError> {
+    value.parse().map_err(|_| MdbError::Corrupted)
+}
+
+/// Add `version` as the next leaf of its bucket/env within the transaction of `db`,
+/// a version that already is a leaf keeps its index
+pub fn append_leaf(db: &Database, bucket: &str, env: &str, version: &str) -> Result<usize, MdbError> {
+    match db.get::<&str>(&version_leaf_key(version)) {
+        Ok(index) => return parse_index(index),
+        Err(MdbError::NotFound) => {},
+        Err(err) => return Err(err)
+    };
+    let count_key = leaf_count_key(bucket, env);
+    let index = match db.get::<&str>(&count_key) {
+        Ok(count) => parse_index(count)?,
+        Err(MdbError::NotFound) => 0,
+        Err(err) => return Err(err)
+    };
+    db.set(&leaf_key(bucket, env, index), &version)?;
+    db.set(&version_leaf_key(version), &index.to_string())?;
+    db.set(&count_key, &(index + 1).to_string())?;
+    Ok(index)
+}
+
+impl LmdbContext {
+
+    fn leaf_count(&self, bucket: &str, env: &str) -> Result<usize, MdbError> {
+        match self.get(&leaf_count_key(bucket, env)) {
+            Ok(count) => parse_index(&count),
+            Err(MdbError::NotFound) => Ok(0),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Versions of the first `count` leaves of a bucket/env, oldest first
+    fn get_leaves(&self, bucket: &str, env: &str, count: usize) -> Result<Vec<String>, MdbError> {
+        let mut versions = Vec::with_capacity(count);
+        for index in 0..count {
+            versions.push(self.get(&leaf_key(bucket, env, index))?);
+        }
+        Ok(versions)
+    }
+
+    /// Versions of all events stored for a bucket/env, oldest first
+    pub fn get_log(&self, bucket: &str, env: &str) -> Result<Vec<String>, MdbError> {
+        let count = self.leaf_count(bucket, env)?;
+        self.get_leaves(bucket, env, count)
+    }
+
+    /// Sealed root of a bucket/env by sequence, the latest one without
+    pub fn get_merkle_root(&self, bucket: &str, env: &str, sequence: Option<u64>) -> Result<Option<MerkleRoot>, MdbError> {
+        let key = match sequence {
+            Some(sequence) => sealed_root_key(bucket, env, sequence),
+            None => root_key(bucket, env)
+        };
+        match self.get(&key) {
+            Ok(root) => match serde_json::from_str(&root) {
+                Ok(merkle_root) => Ok(Some(merkle_root)),
+                Err(err) => {
+                    error!("Error while parsing merkle root bucket={} env={} err={}", bucket, env, err);
+                    Ok(None)
+                }
+            },
+            Err(MdbError::NotFound) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn event_hashes(&self, versions: &[String]) -> Result<Vec<String>, MdbError> {
+        let mut hashes = Vec::with_capacity(versions.len());
+        for version in versions {
+            match self.get_version(version)? {
+                // events stored before chaining have no hash, use what it would have been
+                Some(evt) => hashes.push(evt.hash.clone().unwrap_or_else(|| event_hash(&evt))),
+                None => return Err(MdbError::NotFound)
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Seal a new root over every event stored so far for a bucket/env
+    pub fn seal_merkle_root(&self, bucket: &str, env: &str) -> Result<Option<MerkleRoot>, MdbError> {
+        let versions = self.get_log(bucket, env)?;
+        let hashes = self.event_hashes(&versions)?;
+        let root = match merkle_root(&hashes) {
+            Some(root) => root,
+            None => return Ok(None)
+        };
+        let sequence = match self.get_merkle_root(bucket, env, None)? {
+            Some(previous) => previous.sequence + 1,
+            None => 0
+        };
+        let merkle_root = MerkleRoot {
+            bucket: String::from(bucket),
+            env: String::from(env),
+            sequence: sequence,
+            root: root,
+            leaf_count: hashes.len(),
+            sealed_at: Utc::now().to_rfc3339()
+        };
+        match serde_json::to_string(&merkle_root) {
+            Ok(value) => {
+                // keep every sealed root, roots already handed out must stay verifiable
+                self.set(&sealed_root_key(bucket, env, sequence), &value)?;
+                self.set(&root_key(bucket, env), &value)?;
+                info!("Sealed merkle root bucket={} env={} sequence={} leaves={}", bucket, env, sequence, merkle_root.leaf_count);
+                Ok(Some(merkle_root))
+            },
+            Err(err) => {
+                error!("Error while serializing merkle root err={}", err);
+                Ok(None)
+            }
+        }
+    }
+
+    /// True when events were stored since the latest root and that root, if any, is older
+    /// than the seal interval
+    pub fn merkle_root_due(&self, bucket: &str, env: &str) -> bool {
+        let leaf_count = match self.leaf_count(bucket, env) {
+            Ok(leaf_count) => leaf_count,
+            Err(_) => return false
+        };
+        match self.get_merkle_root(bucket, env, None) {
+            Ok(Some(root)) => leaf_count > root.leaf_count && match root.sealed_at.parse::<DateTime<Utc>>() {
+                Ok(sealed_at) => Utc::now() - sealed_at > Duration::seconds(self.merkle_seal_interval_secs),
+                Err(_) => true
+            },
+            Ok(None) => leaf_count > 0,
+            Err(_) => false
+        }
+    }
+
+    /// Every bucket/env with stored events
+    pub fn merkle_logs(&self) -> Result<Vec<(String, String)>, MdbError> {
+        let reader = self.env.get_reader()?;
+        let db = reader.bind(&self.db_handle);
+        // '}' follows '|', so the range holds exactly the leaf count keys
+        let (first, last) = (LEAF_COUNT_PREFIX, "leaves}");
+        let mut logs = Vec::new();
+        for item in db.keyrange(&first, &last)? {
+            let key : &str = item.get_key();
+            let mut parts = key[LEAF_COUNT_PREFIX.len()..].splitn(2, '|');
+            if let (Some(bucket), Some(env)) = (parts.next(), parts.next()) {
+                logs.push((String::from(bucket), String::from(env)));
+            }
+        }
+        Ok(logs)
+    }
+
+    /// Seal a root for every bucket/env that is due
+    pub fn seal_due_roots(&self) -> Result<(), MdbError> {
+        for (bucket, env) in self.merkle_logs()? {
+            if self.merkle_root_due(&bucket, &env) {
+                self.seal_merkle_root(&bucket, &env)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inclusion proof for a version against the root sealed as `root_sequence`, the latest
+    /// one without. `None` when the version or root is unknown or the version was stored after
+    /// that root was sealed.
+    pub fn merkle_proof(&self, version: &str, root_sequence: Option<u64>) -> Result<Option<MerkleProof>, MdbError> {
+        let (bucket, env) = match self.get_version(version)? {
+            Some(evt) => (String::from(evt.sys.bucket), String::from(evt.sys.env)),
+            None => return Ok(None)
+        };
+        let root = match self.get_merkle_root(&bucket, &env, root_sequence)? {
+            Some(root) => root,
+            None => return Ok(None)
+        };
+        let leaf_index = match self.get(&version_leaf_key(version)) {
+            Ok(index) => parse_index(&index)?,
+            Err(MdbError::NotFound) => return Ok(None),
+            Err(err) => return Err(err)
+        };
+        if leaf_index >= root.leaf_count {
+            return Ok(None);
+        }
+        let sealed_versions = self.get_leaves(&bucket, &env, root.leaf_count)?;
+        let hashes = self.event_hashes(&sealed_versions)?;
+        Ok(Some(MerkleProof {
+            version: String::from(version),
+            event_hash: hashes[leaf_index].clone(),
+            leaf_index: leaf_index,
+            path: merkle_path(&hashes, leaf_index),
+            root: root
+        }))
+    }
+}
+
+/// Seal the due roots every `merkle_seal_interval_secs` in a thread of its own, so the last
+/// events of a bucket/env get a root even when no more events arrive
+pub fn start_merkle_sealer(lmdb_ctx: Arc<LmdbContext>) -> JoinHandle<()> {
+    let interval = time::Duration::from_secs(lmdb_ctx.merkle_seal_interval_secs.max(1) as u64);
+    thread::Builder::new()
+        .name("merkle-sealer".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = lmdb_ctx.seal_due_roots() {
+                error!("Error while sealing merkle roots err={}", err);
+            }
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::DocumentState;
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    fn hashes(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("hash{}", index)).collect()
+    }
+
+    fn proof(event_hashes: &[String], index: usize) -> MerkleProof {
+        MerkleProof {
+            version: format!("v{}", index),
+            event_hash: event_hashes[index].clone(),
+            leaf_index: index,
+            path: merkle_path(event_hashes, index),
+            root: MerkleRoot {
+                bucket: String::from("b"),
+                env: String::from("e"),
+                sequence: 0,
+                root: merkle_root(event_hashes).unwrap(),
+                leaf_count: event_hashes.len(),
+                sealed_at: String::new()
+            }
+        }
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        assert_eq!(merkle_root(&[]), None);
+        for count in 1..10 {
+            let event_hashes = hashes(count);
+            for index in 0..count {
+                assert!(proof(&event_hashes, index).verify(), "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn other_event_does_not_prove() {
+        let event_hashes = hashes(5);
+        let mut forged = proof(&event_hashes, 3);
+        forged.event_hash = String::from("other");
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn node_does_not_pass_as_leaf() {
+        let event_hashes = hashes(4);
+        let mut forged = proof(&event_hashes, 0);
+        forged.event_hash = node_hash(&leaf_hash(&event_hashes[0]), &leaf_hash(&event_hashes[1]));
+        forged.path.remove(0);
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn stored_versions_prove_against_sealed_roots() {
+        let lmdb_ctx = temp_context();
+        let ids = ["d0", "d1", "d2", "d3"];
+        let versions = ["v0", "v1", "v2", "v3"];
+        for index in 0..3 {
+            lmdb_ctx.set_event(&event(ids[index], versions[index], DocumentState::Draft, None)).unwrap();
+        }
+        // storing an event again keeps its leaf
+        lmdb_ctx.set_event(&event("d1", "v1", DocumentState::Draft, None)).unwrap();
+        assert_eq!(lmdb_ctx.get_log("b", "e").unwrap(), vec!["v0", "v1", "v2"]);
+
+        let first_root = lmdb_ctx.seal_merkle_root("b", "e").unwrap().unwrap();
+        assert_eq!((first_root.sequence, first_root.leaf_count), (0, 3));
+        lmdb_ctx.set_event(&event(ids[3], versions[3], DocumentState::Draft, None)).unwrap();
+        assert!(lmdb_ctx.merkle_proof("v3", None).unwrap().is_none());
+
+        let second_root = lmdb_ctx.seal_merkle_root("b", "e").unwrap().unwrap();
+        assert_eq!((second_root.sequence, second_root.leaf_count), (1, 4));
+        for version in &versions {
+            let latest_proof = lmdb_ctx.merkle_proof(version, None).unwrap().unwrap();
+            assert!(latest_proof.verify());
+            assert_eq!(latest_proof.root.sequence, 1);
+        }
+        let first_proof = lmdb_ctx.merkle_proof("v1", Some(0)).unwrap().unwrap();
+        assert!(first_proof.verify());
+        assert_eq!(first_proof.root.root, first_root.root);
+        assert!(lmdb_ctx.merkle_proof("v3", Some(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn due_roots_are_sealed_once_per_change() {
+        let lmdb_ctx = temp_context();
+        let mut other = event("d1", "v1", DocumentState::Draft, None);
+        other.sys.env = "other";
+        lmdb_ctx.set_event(&event("d0", "v0", DocumentState::Draft, None)).unwrap();
+        lmdb_ctx.set_event(&other).unwrap();
+        assert_eq!(lmdb_ctx.merkle_logs().unwrap(), vec![
+            (String::from("b"), String::from("e")),
+            (String::from("b"), String::from("other"))
+        ]);
+
+        lmdb_ctx.seal_due_roots().unwrap();
+        assert_eq!(lmdb_ctx.get_merkle_root("b", "e", None).unwrap().unwrap().sequence, 0);
+        assert_eq!(lmdb_ctx.get_merkle_root("b", "other", None).unwrap().unwrap().sequence, 0);
+        // nothing new to seal, and the interval has not passed yet
+        assert!(!lmdb_ctx.merkle_root_due("b", "e"));
+        lmdb_ctx.set_event(&event("d2", "v2", DocumentState::Draft, None)).unwrap();
+        assert!(!lmdb_ctx.merkle_root_due("b", "e"));
+    }
+}
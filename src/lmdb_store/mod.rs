@@ -5,28 +5,34 @@ extern crate ws;
 use self::lmdb_rs::{Database, DbHandle, Environment, EnvBuilder, DbFlags};
 use self::lmdb_rs::core::MdbError;
 use std::result::Result;
-use domain::{LedgerEvent};
+use domain::{DocumentState, LedgerEvent};
+use domain::diff::{diff_events, DiffError, VersionDiff};
+use domain::hash::{verify_event, ChainError};
 use kafka::LedgerEvents;
+use config::LmdbSettings;
 use serde_json::Value;
 
+pub mod commands;
+pub mod merkle;
+pub mod schema;
+pub mod sequence;
+
 pub struct LmdbContext {
     pub env: Environment,
-    pub db_handle: DbHandle
+    pub db_handle: DbHandle,
+    pub merkle_seal_interval_secs: i64
 }
 
-pub fn create_context() -> Option<LmdbContext> {
-    /*
-{
- TODO: KOLLA storlek  path: LMDB_DATA_PATH,
-    mapSize: 2 * 1024 * 1024 * 1024, // maximum database size
-    maxDbs: 3
-}
-    */
-        match EnvBuilder::new().open("test-lmdb", 0o777) {
+pub fn create_context(settings: &LmdbSettings) -> Option<LmdbContext> {
+        match EnvBuilder::new().map_size(settings.map_size).open(&settings.path, 0o777) {
             Ok(environment) => {
                 match environment.get_default_db(DbFlags::empty()) {
                     Ok(db) => {
-                        Some(LmdbContext{env: environment, db_handle: db})
+                        Some(LmdbContext{
+                            env: environment,
+                            db_handle: db,
+                            merkle_seal_interval_secs: settings.merkle_seal_interval_secs
+                        })
                     },
                     Err(_) => None
                 }
@@ -35,6 +41,18 @@ pub fn create_context() -> Option<LmdbContext> {
         }
 }
 
+/// Context on a new, empty env in the temp dir
+#[cfg(test)]
+pub fn temp_context() -> LmdbContext {
+    let path = std::env::temp_dir().join(format!("toamend-test-{}", ::uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&path).unwrap();
+    create_context(&LmdbSettings {
+        path: path.to_string_lossy().into_owned(),
+        map_size: 64 * 1024 * 1024,
+        merkle_seal_interval_secs: 300
+    }).unwrap()
+}
+
 impl LmdbContext {
 
     pub fn get(&self, key: &str) -> Result<String, MdbError> {
@@ -82,6 +100,15 @@ impl LmdbContext {
             }
     }
 
+    /// Latest version of a document for queries, archived documents are `NotFound` unless
+    /// `include_archived` is set
+    pub fn get_current(&self, id: &str, include_archived: bool) -> Result<Option<LedgerEvent<Value>>, MdbError> {
+        match self.get_latest(id)? {
+            Some(ref latest) if !include_archived && latest.state() == DocumentState::Archived => Err(MdbError::NotFound),
+            latest => Ok(latest)
+        }
+    }
+
     pub fn get_previous(&self, version: &str, limit: u8) -> Result<Vec<LedgerEvent<Value>>, MdbError> {
         info!("get_previous version={} limit={}", version, limit);
         let mut result = Vec::new();
@@ -174,7 +201,94 @@ impl LmdbContext {
         }
     }
 
-    
+    pub fn get_version(&self, version: &str) -> Result<Option<LedgerEvent<Value>>, MdbError> {
+        info!("get_version {}", version);
+        match self.env.get_reader() {
+            Ok(reader) => {
+                let db : Database = reader.bind(&self.db_handle);
+                match db.get::<&str>(&version) {
+                    Ok(data_version) => match serde_json::from_str::<LedgerEvent<Value>>(data_version) {
+                        Ok(evt) => Ok(Some(evt)),
+                        Err(err) => {
+                            error!("Error while parsing data_version={} err={}", data_version, err);
+                            Ok(None)
+                        }
+                    },
+                    Err(MdbError::NotFound) => Ok(None),
+                    Err(err) => Err(err)
+                }
+            },
+            Err(err) => {
+                error!("Error while getting reader {}", err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Walk the history of a document from its latest version and verify every event
+    /// against its payload and the hash of the version before it, archived documents are
+    /// `NotFound` unless `include_archived` is set.
+    /// Returns the number of verified versions.
+    pub fn verify_history(&self, id: &str, include_archived: bool) -> Result<Result<usize, ChainError>, MdbError> {
+        let mut current = match self.get_current(id, include_archived)? {
+            Some(latest) => latest,
+            None => return Ok(Ok(0))
+        };
+        let mut count = 0;
+        loop {
+            let previous = match current.sys.previous_version {
+                Some(previous_version) => self.get_version(previous_version)?,
+                None => None
+            };
+            if let Err(chain_error) = verify_event(&current, previous.as_ref()) {
+                return Ok(Err(chain_error));
+            }
+            count += 1;
+            match previous {
+                Some(previous_event) => current = previous_event,
+                None => return Ok(Ok(count))
+            }
+        }
+    }
+
+    /// Keep an event that failed verification out of the document history, stored by event id
+    /// with the reason so it can be inspected
+    pub fn quarantine_event(&self, event: &LedgerEvent<Value>, chain_error: &ChainError) -> Result<(), MdbError> {
+        let quarantined = json!({"error": format!("{}", chain_error), "event": event});
+        self.set(&format!("quarantine|{}", event.event_id), &quarantined.to_string())
+    }
+
+    /// Diff between two versions of the document `id`. `to` defaults to the latest version,
+    /// `from` to the version before `to`. Archived documents are unknown unless `include_archived` is set.
+    pub fn diff_versions(&self, id: &str, from: Option<&str>, to: Option<&str>, include_archived: bool) -> Result<Result<VersionDiff, DiffError>, MdbError> {
+        let latest = match self.get_current(id, include_archived) {
+            Ok(Some(latest)) => latest,
+            Ok(None) | Err(MdbError::NotFound) => return Ok(Err(DiffError::UnknownDocument{id: String::from(id)})),
+            Err(err) => return Err(err)
+        };
+        let to_event = match to {
+            Some(to_version) => self.get_version(to_version)?,
+            None => Some(latest)
+        };
+        let to_event = match to_event {
+            Some(evt) => evt,
+            None => return Ok(Err(DiffError::UnknownVersion{version: String::from(to.unwrap_or(id))}))
+        };
+        let from_version = match from.or(to_event.sys.previous_version) {
+            Some(from_version) => String::from(from_version),
+            None => return Ok(Err(DiffError::FirstVersion{version: String::from(to_event.sys.version)}))
+        };
+        let from_event = match self.get_version(&from_version)? {
+            Some(evt) => evt,
+            None => return Ok(Err(DiffError::UnknownVersion{version: from_version}))
+        };
+        for evt in &[&from_event, &to_event] {
+            if evt.sys.id != id {
+                return Ok(Err(DiffError::DifferentDocuments{id: String::from(id), other_id: String::from(evt.sys.id)}));
+            }
+        }
+        Ok(Ok(diff_events(&from_event, &to_event)))
+    }
 
     pub fn set(&self, key: &str, value: &str) -> Result<(), MdbError> {
         let txn = match self.env.new_transaction() {
@@ -198,28 +312,46 @@ impl LmdbContext {
                 {
                     let db = txn.bind(&self.db_handle);
                     db.set(&event.sys.version, &value)?;
-                    match db.get::<&str>(&event.sys.id) {
-                        Ok(_) => {
+                    let head = match db.get::<&str>(&event.sys.id) {
+                        Ok(head) => Some(String::from(head)),
+                        Err(MdbError::NotFound) => None,
+                        Err(err) => return Err(err)
+                    };
+                    // the command worker stores its events before the event consumer does, so an
+                    // event can arrive twice. Only an event that follows the head moves it.
+                    let in_history = match head {
+                        Some(ref head) if head == event.sys.version => {
+                            debug!("SET: Known id: {} version: {}", event.sys.id, event.sys.version);
+                            true
+                        },
+                        Some(ref head) if event.sys.previous_version == Some(&head[..]) => {
                             // there is a previous version linked to this id
-                            println!("SET: Upd id: {} version: {}", event.sys.id, event.sys.version);
+                            debug!("SET: Upd id: {} version: {}", event.sys.id, event.sys.version);
                             db.del(&event.sys.id)?;
                             db.set(&event.sys.id, &event.sys.version)?;
-                            Ok(())
+                            true
                         },
-                        Err(err) => {
-                            match err {
-                                MdbError::NotFound => {
-                                    println!("SET: New id: {} version: {}", event.sys.id, event.sys.version);
-
-                                    db.set(&event.sys.id, &event.sys.version)?;
-                                    Ok(())
-                                },
-                                _ => Err(err)
-                            }
+                        Some(ref head) => {
+                            info!("SET: Keep head id: {} head: {}, version {} does not follow it", event.sys.id, head, event.sys.version);
+                            false
+                        },
+                        None => {
+                            debug!("SET: New id: {} version: {}", event.sys.id, event.sys.version);
+                            db.set(&event.sys.id, &event.sys.version)?;
+                            true
                         }
-                    }?;
+                    };
+
+                    // a version off the history of its document is kept, but not logged or indexed
+                    if in_history {
+                        // append to the log of the bucket/env, the leaves of its merkle tree
+                        merkle::append_leaf(&db, event.sys.bucket, event.sys.env, event.sys.version)?;
+
+                        sequence::append_sequence(&db, event.sys.version)?;
+                        schema::index_schema(&db, event)?;
+                    }
+                    commands::record_accepted(&db, event)?;
                 }
-                println!("Finsihed ok");
                 txn.commit()?;
                 Ok(())
                         
@@ -275,7 +407,68 @@ fn keys(event: &LedgerEvent<Value>) -> (String, String) {
 
 impl LedgerEvents for LmdbContext {
     fn on_event(&self, event: &LedgerEvent<Value>) {
-        println!("Received event {} with checksum {} to LMDB store", event.event_id, event.sys.payload_checksum.unwrap());
-        self.set_event(event).unwrap();
+        println!("Received event {} version {} to LMDB store", event.event_id, event.sys.version);
+        let previous = match event.sys.previous_version {
+            Some(previous_version) => self.get_version(previous_version).unwrap_or(None),
+            None => None
+        };
+        match verify_event(event, previous.as_ref()) {
+            Ok(()) => {},
+            // events from before chaining have no hash, they are only taken on history that is not chained yet
+            Err(ChainError::MissingHash{..}) if previous.as_ref().map_or(true, |previous_event| previous_event.hash.is_none()) =>
+                warn!("Storing unchained event {} version {}", event.event_id, event.sys.version),
+            Err(chain_error) => {
+                error!("Event {} does not verify, quarantined: {}", event.event_id, chain_error);
+                if let Err(err) = self.quarantine_event(event, &chain_error) {
+                    error!("Error while quarantining event {} err={}", event.event_id, err);
+                }
+                return;
+            }
+        }
+        // roots are sealed by `merkle::start_merkle_sealer`
+        if let Err(err) = self.set_event(event) {
+            error!("Error while storing event {} err={}", event.event_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::fixtures::event;
+
+    #[test]
+    fn version_off_the_history_is_not_logged() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("doc", "v1", DocumentState::Draft, None)).unwrap();
+        let mut next = event("doc", "v2", DocumentState::Draft, None);
+        next.sys.previous_version = Some("v1");
+        lmdb_ctx.set_event(&next).unwrap();
+        let mut stale = event("doc", "v3", DocumentState::Draft, None);
+        stale.sys.previous_version = Some("v1");
+        lmdb_ctx.set_event(&stale).unwrap();
+
+        assert_eq!(lmdb_ctx.get("doc").unwrap(), "v2");
+        assert!(lmdb_ctx.get_version("v3").unwrap().is_some());
+        assert_eq!(lmdb_ctx.get_log("b", "e").unwrap(), vec!["v1", "v2"]);
+        assert_eq!(lmdb_ctx.latest_sequence().unwrap(), 2);
+    }
+
+    #[test]
+    fn archived_documents_are_only_read_on_request() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("doc", "v1", DocumentState::Draft, None)).unwrap();
+        let mut archived = event("doc", "v2", DocumentState::Archived, None);
+        archived.sys.previous_version = Some("v1");
+        lmdb_ctx.set_event(&archived).unwrap();
+
+        assert!(match lmdb_ctx.get_current("doc", false) { Err(MdbError::NotFound) => true, _ => false });
+        assert!(match lmdb_ctx.verify_history("doc", false) { Err(MdbError::NotFound) => true, _ => false });
+        assert_eq!(lmdb_ctx.diff_versions("doc", None, None, false).unwrap().unwrap_err(),
+            DiffError::UnknownDocument{id: String::from("doc")});
+
+        assert_eq!(lmdb_ctx.get_current("doc", true).unwrap().unwrap().sys.version, "v2");
+        assert!(lmdb_ctx.verify_history("doc", true).is_ok());
+        assert_eq!(lmdb_ctx.diff_versions("doc", None, None, true).unwrap().unwrap().from_version, "v1");
     }
 }
@@ -0,0 +1,50 @@
+extern crate lmdb_rs;
+extern crate serde_json;
+
+use self::lmdb_rs::Database;
+use self::lmdb_rs::core::MdbError;
+
+use domain::LedgerEvent;
+use domain::schema::{ContentTypeSchema, SCHEMA_CATEGORY};
+use lmdb_store::LmdbContext;
+use serde_json::Value;
+
+fn schema_key(bucket: &str, env: &str, name: &str) -> String {
+    format!("schema|{}|{}|{}", bucket, env, name)
+}
+
+/// Point the name of a content type document at its id within the transaction of `db`.
+/// A deleted or renamed document leaves its old entry, `get_schema` skips those.
+pub fn index_schema(db: &Database, event: &LedgerEvent<Value>) -> Result<(), MdbError> {
+    if event.sys.category != SCHEMA_CATEGORY {
+        return Ok(());
+    }
+    match event.payload.as_ref().and_then(|payload| payload.get("name")).and_then(Value::as_str) {
+        Some(name) => db.set(&schema_key(event.sys.bucket, event.sys.env, name), &event.sys.id),
+        None => Ok(())
+    }
+}
+
+impl LmdbContext {
+
+    /// Schema of the latest version of the content type document named `name` in a bucket/env
+    pub fn get_schema(&self, bucket: &str, env: &str, name: &str) -> Result<Option<ContentTypeSchema>, MdbError> {
+        let id = match self.get(&schema_key(bucket, env, name)) {
+            Ok(id) => id,
+            Err(MdbError::NotFound) => return Ok(None),
+            Err(err) => return Err(err)
+        };
+        let payload = match self.get_latest(&id)? {
+            Some(LedgerEvent{payload: Some(payload), ..}) => payload,
+            _ => return Ok(None)
+        };
+        match serde_json::from_value::<ContentTypeSchema>(payload) {
+            Ok(ref schema) if schema.name != name => Ok(None),
+            Ok(schema) => Ok(Some(schema)),
+            Err(err) => {
+                error!("Error while parsing schema of content type {} err={}", name, err);
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+extern crate lmdb_rs;
+
+use self::lmdb_rs::Database;
+use self::lmdb_rs::core::MdbError;
+
+use domain::LedgerEvent;
+use lmdb_store::LmdbContext;
+use serde_json::Value;
+
+// every stored event gets the next number of a single counter, so events can be read back
+// in the order this store received them
+
+const SEQUENCE_KEY : &str = "seq";
+
+fn sequence_key(sequence: u64) -> String {
+    format!("seq|{:020}", sequence)
+}
+
+fn version_sequence_key(version: &str) -> String {
+    format!("seqof|{}", version)
+}
+
+fn parse_sequence(value: &str) -> Result<u64, MdbError> {
+    value.parse().map_err(|_| MdbError::Corrupted)
+}
+
+/// Give `version` the next sequence number within the transaction of `db`,
+/// a version that already has one keeps it
+pub fn append_sequence(db: &Database, version: &str) -> Result<u64, MdbError> {
+    match db.get::<&str>(&version_sequence_key(version)) {
+        Ok(sequence) => return parse_sequence(sequence),
+        Err(MdbError::NotFound) => {},
+        Err(err) => return Err(err)
+    };
+    let sequence = match db.get::<&str>(&SEQUENCE_KEY) {
+        Ok(latest) => parse_sequence(latest)? + 1,
+        Err(MdbError::NotFound) => 1,
+        Err(err) => return Err(err)
+    };
+    db.set(&SEQUENCE_KEY, &sequence.to_string())?;
+    db.set(&sequence_key(sequence), &version)?;
+    db.set(&version_sequence_key(version), &sequence.to_string())?;
+    Ok(sequence)
+}
+
+impl LmdbContext {
+
+    /// Sequence number of the latest stored event, 0 when the store is empty
+    pub fn latest_sequence(&self) -> Result<u64, MdbError> {
+        match self.get(SEQUENCE_KEY) {
+            Ok(latest) => parse_sequence(&latest),
+            Err(MdbError::NotFound) => Ok(0),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// `None` when the version was not stored
+    pub fn get_sequence(&self, version: &str) -> Result<Option<u64>, MdbError> {
+        match self.get(&version_sequence_key(version)) {
+            Ok(sequence) => parse_sequence(&sequence).map(Some),
+            Err(MdbError::NotFound) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Events stored after `after` up to and including `up_to` for which `matches` holds, oldest first
+    pub fn events_between<F>(&self, after: u64, up_to: u64, matches: F) -> Result<Vec<LedgerEvent<Value>>, MdbError>
+        where F: Fn(&LedgerEvent<Value>) -> bool {
+        let mut events = Vec::new();
+        for sequence in (after + 1)..(up_to + 1) {
+            let version = match self.get(&sequence_key(sequence)) {
+                Ok(version) => version,
+                Err(MdbError::NotFound) => continue,
+                Err(err) => return Err(err)
+            };
+            match self.get_version(&version)? {
+                Some(evt) => if matches(&evt) {
+                    events.push(evt);
+                },
+                None => warn!("Sequence {} points to missing version {}", sequence, version)
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::DocumentState;
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    #[test]
+    fn events_are_read_back_in_the_order_they_were_stored() {
+        let lmdb_ctx = temp_context();
+        assert_eq!(lmdb_ctx.latest_sequence().unwrap(), 0);
+        lmdb_ctx.set_event(&event("d1", "v1", DocumentState::Draft, None)).unwrap();
+        lmdb_ctx.set_event(&event("d2", "v2", DocumentState::Draft, None)).unwrap();
+        lmdb_ctx.set_event(&event("d1", "v1", DocumentState::Draft, None)).unwrap();
+        lmdb_ctx.set_event(&event("d3", "v3", DocumentState::Draft, None)).unwrap();
+
+        assert_eq!(lmdb_ctx.latest_sequence().unwrap(), 3);
+        assert_eq!(lmdb_ctx.get_sequence("v1").unwrap(), Some(1));
+        assert_eq!(lmdb_ctx.get_sequence("v9").unwrap(), None);
+        let since_first : Vec<String> = lmdb_ctx.events_between(1, 3, |evt| evt.sys.id != "d2").unwrap()
+            .into_iter().map(|evt| String::from(evt.sys.version)).collect();
+        assert_eq!(since_first, vec!["v3"]);
+    }
+}
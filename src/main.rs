@@ -0,0 +1,432 @@
+#[macro_use]
+extern crate clap;
+extern crate pretty_env_logger;
+extern crate toamend;
+
+extern crate futures;
+extern crate serde_json;
+extern crate uuid;
+
+use std::fs;
+use std::sync::Arc;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::Future;
+use serde_json::Value;
+use uuid::Uuid;
+
+use toamend::auth::{create_authenticator, Authenticator};
+use toamend::config::Settings;
+use toamend::domain::{Action, LedgerCommand, PayloadPatch, Revision};
+use toamend::kafka::{start_cmd_workers, LedgerEventsConsumer};
+use toamend::kafka::producer::{create_producer, produce_command};
+use toamend::kafka::dead_letter::{list_dead_letters, redrive_dead_letters};
+use toamend::lmdb_store::create_context;
+use toamend::lmdb_store::merkle::start_merkle_sealer;
+use toamend::migration::MigrationSpec;
+use toamend::redis_event_store::new_connection_pool;
+use toamend::server::http;
+use toamend::server::ws::WsContext;
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn authenticator(settings: &Settings) -> Box<Authenticator> {
+    create_authenticator(&settings.auth).unwrap_or_else(|err| exit_with(&format!("{}", err)))
+}
+
+/// Store events in the given sinks. The `ws` sink pushes them to the WebSocket clients of this
+/// process and stores them in its LMDB, which it then also serves as the `lmdb` sink. The
+/// merkle roots of the LMDB are sealed periodically.
+fn consume_events(settings: &Settings, sinks: &[&str], group: Option<&str>) {
+    let ws_ctx = if sinks.contains(&"ws") {
+        Some(WsContext::new(settings, authenticator(settings)))
+    } else {
+        None
+    };
+    // an LMDB env may only be opened once per process
+    let lmdb_ctx = if sinks.contains(&"lmdb") && ws_ctx.is_none() {
+        Some(Arc::new(create_context(&settings.lmdb).unwrap_or_else(|| exit_with("Could not open LMDB store"))))
+    } else {
+        None
+    };
+    let redis_ctx = if sinks.contains(&"redis") {
+        Some(new_connection_pool(&settings.redis).unwrap_or_else(|| exit_with("Could not connect to redis")))
+    } else {
+        None
+    };
+    // every WebSocket server needs all events and subscriptions, so it gets a group of its own
+    let group = match group {
+        Some(group) => String::from(group),
+        None if ws_ctx.is_some() => format!("ws-server-{}", Uuid::new_v4().to_hyphenated()),
+        None => settings.kafka.event_group.clone()
+    };
+
+    let server_thread = ws_ctx.as_ref().map(|ctx| ctx.start_server());
+    if let Some(ctx) = ws_ctx.as_ref().map(|ctx| &ctx.lmdb_ctx).or(lmdb_ctx.as_ref()) {
+        start_merkle_sealer(ctx.clone());
+    }
+
+    let mut consumer = LedgerEventsConsumer::new(&settings.kafka, &group, ws_ctx.is_some());
+    // store first, replays for new subscribers are read from the store
+    if let Some(ref ctx) = ws_ctx {
+        consumer.add_events_hook(&*ctx.lmdb_ctx);
+    }
+    if let Some(ref ctx) = lmdb_ctx {
+        consumer.add_events_hook(&**ctx);
+    }
+    if let Some(ref ctx) = redis_ctx {
+        consumer.add_events_hook(ctx);
+    }
+    if let Some(ref ctx) = ws_ctx {
+        consumer.add_events_hook(ctx);
+    }
+    consumer.process_events();
+
+    if let Some(server_thread) = server_thread {
+        server_thread.join().unwrap();
+    }
+}
+
+fn event_consumer(settings: &Settings, matches: &ArgMatches) {
+    let sinks : Vec<&str> = matches.values_of("sink").unwrap().collect();
+    consume_events(settings, &sinks, matches.value_of("group"));
+}
+
+fn ws_server(settings: &Settings, matches: &ArgMatches) {
+    consume_events(settings, &["ws"], matches.value_of("group"));
+}
+
+fn required<'a>(matches: &'a ArgMatches, name: &str) -> &'a str {
+    matches.value_of(name).unwrap_or_else(|| exit_with(&format!("--{} is required for this action", name)))
+}
+
+fn revision<'a>(matches: &'a ArgMatches) -> Revision<'a> {
+    Revision {
+        id: required(matches, "id"),
+        version: required(matches, "version")
+    }
+}
+
+fn send_command(settings: &Settings, matches: &ArgMatches) {
+    let action = match matches.value_of("action").unwrap() {
+        "create" => Action::CREATE {
+            category: required(matches, "category"),
+            content_type: required(matches, "content-type"),
+            bucket: required(matches, "bucket"),
+            env: required(matches, "env")
+        },
+        "update" => Action::UPDATE(revision(matches)),
+        "delete" => Action::DELETE(revision(matches)),
+        "copy" => Action::COPY(revision(matches)),
+        "seal" => Action::SEAL(revision(matches)),
+        "publish" => Action::PUBLISH(revision(matches)),
+        "unpublish" => Action::UNPUBLISH(revision(matches)),
+        "archive" => Action::ARCHIVE(revision(matches)),
+        "unarchive" => Action::UNARCHIVE(revision(matches)),
+        "revert" => Action::REVERT {
+            id: required(matches, "id"),
+            version: required(matches, "version"),
+            to_version: required(matches, "to-version")
+        },
+        _ => unreachable!()
+    };
+
+    let payload_json = match (matches.value_of("payload"), matches.value_of("payload-file")) {
+        (Some(json), _) => Some(String::from(json)),
+        (None, Some(file)) => Some(fs::read_to_string(file).unwrap_or_else(|err| exit_with(&format!("Could not read {}: {}", file, err)))),
+        (None, None) => None
+    };
+    let payload : Option<Value> = payload_json.map(|json| serde_json::from_str(&json)
+        .unwrap_or_else(|err| exit_with(&format!("Invalid payload: {}", err))));
+    let patch_json = |name: &str| matches.value_of(name).map(|json| serde_json::from_str::<Value>(json)
+        .unwrap_or_else(|err| exit_with(&format!("Invalid --{}: {}", name, err))));
+    let patch = match (patch_json("json-patch"), patch_json("merge-patch")) {
+        (Some(operations), _) => Some(PayloadPatch::JsonPatch(operations)),
+        (None, Some(merge_patch)) => Some(PayloadPatch::MergePatch(merge_patch)),
+        (None, None) => None
+    };
+
+    let command = LedgerCommand {
+        tracking_id: matches.value_of("tracking-id").unwrap_or(""),
+        action: action,
+        payload: payload,
+        user_id: None,
+        roles: Vec::new(),
+        patch: patch
+    };
+    let identity = authenticator(settings).authenticate(matches.value_of("token").unwrap())
+        .unwrap_or_else(|err| exit_with(&format!("{}", err)));
+    let producer = create_producer(&settings.kafka.brokers);
+    match produce_command(&producer, &settings.kafka.command_topic, command, &identity).wait() {
+        Ok(tracking_id) => println!("{}", tracking_id),
+        Err(message) => exit_with(&message)
+    }
+}
+
+fn migrate(settings: &Settings, matches: &ArgMatches) {
+    let spec_file = matches.value_of("spec").unwrap();
+    let spec_json = fs::read_to_string(spec_file).unwrap_or_else(|err| exit_with(&format!("Could not read {}: {}", spec_file, err)));
+    let spec : MigrationSpec = serde_json::from_str(&spec_json).unwrap_or_else(|err| exit_with(&format!("Invalid migration spec: {}", err)));
+    let lmdb_ctx = create_context(&settings.lmdb).unwrap_or_else(|| exit_with("Could not open LMDB store"));
+
+    let include_archived = matches.is_present("include-archived");
+    let reports = if matches.is_present("dry-run") {
+        spec.plan(&lmdb_ctx, include_archived)
+    } else {
+        let token = matches.value_of("token").unwrap_or_else(|| exit_with("--token is required unless --dry-run"));
+        let identity = authenticator(settings).authenticate(token).unwrap_or_else(|err| exit_with(&format!("{}", err)));
+        spec.run(&lmdb_ctx, &settings.kafka, &identity, include_archived)
+    }.unwrap_or_else(|err| exit_with(&format!("Could not read documents: {}", err)));
+
+    for report in &reports {
+        println!("{}", serde_json::to_string(report).unwrap());
+    }
+    let skipped = reports.iter().filter(|report| report.skipped.is_some()).count();
+    let failed = reports.iter().filter(|report| report.error.is_some()).count();
+    eprintln!("{} documents to migrate, {} skipped", reports.len() - skipped, skipped);
+    if failed > 0 {
+        exit_with(&format!("{} UPDATE commands could not be sent", failed));
+    }
+}
+
+fn diff(settings: &Settings, matches: &ArgMatches) {
+    let lmdb_ctx = create_context(&settings.lmdb).unwrap_or_else(|| exit_with("Could not open LMDB store"));
+    let id = matches.value_of("id").unwrap();
+    match lmdb_ctx.diff_versions(id, matches.value_of("from"), matches.value_of("to"), matches.is_present("include-archived")) {
+        Ok(Ok(version_diff)) => println!("{}", serde_json::to_string_pretty(&version_diff).unwrap()),
+        Ok(Err(diff_error)) => exit_with(&format!("{}", diff_error)),
+        Err(err) => exit_with(&format!("Could not read documents: {}", err))
+    }
+}
+
+fn verify(settings: &Settings, matches: &ArgMatches) {
+    let lmdb_ctx = create_context(&settings.lmdb).unwrap_or_else(|| exit_with("Could not open LMDB store"));
+    let id = matches.value_of("id").unwrap();
+    match lmdb_ctx.verify_history(id, matches.is_present("include-archived")) {
+        Ok(Ok(count)) => println!("{} versions of {} verified", count, id),
+        Ok(Err(chain_error)) => exit_with(&format!("{} does not verify: {}", id, chain_error)),
+        Err(err) => exit_with(&format!("Could not read documents: {}", err))
+    }
+}
+
+fn proof(settings: &Settings, matches: &ArgMatches) {
+    let lmdb_ctx = create_context(&settings.lmdb).unwrap_or_else(|| exit_with("Could not open LMDB store"));
+    let version = matches.value_of("version").unwrap();
+    let root_sequence = if matches.is_present("root") {
+        Some(value_t!(matches, "root", u64).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    match lmdb_ctx.merkle_proof(version, root_sequence) {
+        Ok(Some(merkle_proof)) => println!("{}", serde_json::to_string_pretty(&merkle_proof).unwrap()),
+        Ok(None) => exit_with(&format!("{} is not sealed under this root", version)),
+        Err(err) => exit_with(&format!("Could not read documents: {}", err))
+    }
+}
+
+fn dead_letters(settings: &Settings, matches: &ArgMatches) {
+    let brokers = matches.value_of("brokers").unwrap_or(&settings.kafka.brokers);
+    let topic = matches.value_of("topic").unwrap_or(&settings.kafka.dead_letter_topic);
+
+    match matches.subcommand() {
+        ("list", Some(list_matches)) => {
+            let max = value_t!(list_matches, "max", usize).unwrap_or_else(|e| e.exit());
+            for dead_letter in list_dead_letters(brokers, topic, max) {
+                println!("{}", dead_letter);
+            }
+        },
+        ("redrive", Some(redrive_matches)) => {
+            let position = if redrive_matches.is_present("offset") {
+                let partition = value_t!(redrive_matches, "partition", i32).unwrap_or_else(|e| e.exit());
+                let offset = value_t!(redrive_matches, "offset", i64).unwrap_or_else(|e| e.exit());
+                Some((partition, offset))
+            } else {
+                None
+            };
+            let count = redrive_dead_letters(brokers, topic, position);
+            println!("Re-drove {} dead letters", count);
+        },
+        _ => unreachable!()
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let matches = App::new("toamend")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .global(true)
+            .help("TOML config file, defaults to TOAMEND_CONFIG or conf/toamend.toml"))
+        .subcommand(SubCommand::with_name("cmd-worker")
+            .about("Validate commands and emit ledger events"))
+        .subcommand(SubCommand::with_name("event-consumer")
+            .about("Store ledger events in one or more sinks")
+            .arg(Arg::with_name("sink")
+                .long("sink")
+                .takes_value(true)
+                .multiple(true)
+                .required(true)
+                .possible_values(&["lmdb", "redis", "ws"]))
+            .arg(Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .help("consumer group, defaults to kafka.event_group or a group of its own with the ws sink")))
+        .subcommand(SubCommand::with_name("ws-server")
+            .about("Push ledger events to WebSocket clients")
+            .arg(Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .help("consumer group, must be unique per server instance, defaults to a new one")))
+        .subcommand(SubCommand::with_name("http-server")
+            .about("Serve the REST API for commands and documents"))
+        .subcommand(SubCommand::with_name("send-command")
+            .about("Send a command to the command topic and print its tracking id")
+            .arg(Arg::with_name("action")
+                .long("action")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["create", "update", "delete", "copy", "seal", "publish", "unpublish", "archive", "unarchive", "revert"]))
+            .arg(Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .required(true)
+                .env("TOAMEND_TOKEN")
+                .help("token of the user to send the command as"))
+            .arg(Arg::with_name("id").long("id").takes_value(true))
+            .arg(Arg::with_name("version").long("version").takes_value(true))
+            .arg(Arg::with_name("to-version")
+                .long("to-version")
+                .takes_value(true)
+                .help("earlier version whose payload a revert restores"))
+            .arg(Arg::with_name("category").long("category").takes_value(true))
+            .arg(Arg::with_name("content-type").long("content-type").takes_value(true))
+            .arg(Arg::with_name("bucket").long("bucket").takes_value(true))
+            .arg(Arg::with_name("env").long("env").takes_value(true))
+            .arg(Arg::with_name("tracking-id").long("tracking-id").takes_value(true))
+            .arg(Arg::with_name("payload")
+                .long("payload")
+                .takes_value(true)
+                .conflicts_with("payload-file")
+                .help("payload as JSON"))
+            .arg(Arg::with_name("payload-file")
+                .long("payload-file")
+                .takes_value(true)
+                .help("file with the payload as JSON"))
+            .arg(Arg::with_name("json-patch")
+                .long("json-patch")
+                .takes_value(true)
+                .conflicts_with_all(&["payload", "payload-file", "merge-patch"])
+                .help("RFC 6902 operations to apply to the latest payload on update"))
+            .arg(Arg::with_name("merge-patch")
+                .long("merge-patch")
+                .takes_value(true)
+                .conflicts_with_all(&["payload", "payload-file"])
+                .help("RFC 7396 merge patch to apply to the latest payload on update")))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Send an UPDATE for every document of a content type that is not at a schema version yet")
+            .arg(Arg::with_name("spec")
+                .long("spec")
+                .takes_value(true)
+                .required(true)
+                .help("JSON file with content_type, bucket, env, to_version and steps"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("only report what would change"))
+            .arg(Arg::with_name("include-archived")
+                .long("include-archived")
+                .help("report archived documents as skipped instead of leaving them out"))
+            .arg(Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .env("TOAMEND_TOKEN")
+                .help("token of the user to send the updates as")))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Print what changed in the payload and sys fields between two versions of a document")
+            .arg(Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .help("defaults to the version before --to"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .help("defaults to the latest version"))
+            .arg(Arg::with_name("include-archived")
+                .long("include-archived")
+                .help("also diff archived documents")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Verify the hash chain of a document from its latest version back to its first")
+            .arg(Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("include-archived")
+                .long("include-archived")
+                .help("also verify archived documents")))
+        .subcommand(SubCommand::with_name("proof")
+            .about("Print the merkle inclusion proof of a version")
+            .arg(Arg::with_name("version")
+                .long("version")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("root")
+                .long("root")
+                .takes_value(true)
+                .help("sequence of the sealed root to prove against, defaults to the latest")))
+        .subcommand(SubCommand::with_name("dead-letters")
+            .about("Inspect and re-drive commands that could not be processed")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("brokers")
+                .long("brokers")
+                .takes_value(true))
+            .arg(Arg::with_name("topic")
+                .long("topic")
+                .takes_value(true))
+            .subcommand(SubCommand::with_name("list")
+                .about("Print dead letters from the beginning of the topic")
+                .arg(Arg::with_name("max")
+                    .long("max")
+                    .takes_value(true)
+                    .default_value("100")))
+            .subcommand(SubCommand::with_name("redrive")
+                .about("Send dead letters back to their original topic")
+                .arg(Arg::with_name("partition")
+                    .long("partition")
+                    .takes_value(true)
+                    .requires("offset")
+                    .help("source partition of the record to re-drive"))
+                .arg(Arg::with_name("offset")
+                    .long("offset")
+                    .takes_value(true)
+                    .requires("partition")
+                    .help("only re-drive the record with this source offset in --partition"))))
+        .get_matches();
+
+    let settings = match Settings::load(matches.value_of("config")) {
+        Ok(settings) => settings,
+        Err(err) => exit_with(&format!("{}", err))
+    };
+
+    match matches.subcommand() {
+        ("cmd-worker", Some(_)) => start_cmd_workers(&settings),
+        ("event-consumer", Some(sub_matches)) => event_consumer(&settings, sub_matches),
+        ("ws-server", Some(sub_matches)) => ws_server(&settings, sub_matches),
+        ("http-server", Some(_)) => http::start_server(&settings, authenticator(&settings)),
+        ("send-command", Some(sub_matches)) => send_command(&settings, sub_matches),
+        ("migrate", Some(sub_matches)) => migrate(&settings, sub_matches),
+        ("diff", Some(sub_matches)) => diff(&settings, sub_matches),
+        ("verify", Some(sub_matches)) => verify(&settings, sub_matches),
+        ("proof", Some(sub_matches)) => proof(&settings, sub_matches),
+        ("dead-letters", Some(sub_matches)) => dead_letters(&settings, sub_matches),
+        _ => unreachable!()
+    }
+}
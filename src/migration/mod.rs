@@ -0,0 +1,203 @@
+extern crate lmdb_rs;
+extern crate serde_json;
+
+use std::collections::HashSet;
+
+use self::lmdb_rs::core::MdbError;
+
+use auth::Identity;
+use config::KafkaSettings;
+use domain::{Action, DocumentState, LedgerCommand, Revision};
+use futures::Future;
+use kafka::producer::{create_producer, produce_command};
+use lmdb_store::LmdbContext;
+use serde_json::{Map, Value};
+
+/// One change to the top level fields of a payload
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum MigrationStep {
+    Rename{from: String, to: String},
+    /// set `field` when it is missing or null
+    Default{field: String, value: Value},
+    Drop{field: String}
+}
+
+/// Moves the documents of a content type in a bucket/env to version `to_version` of its schema.
+/// Documents already validated against `to_version` or later are left alone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MigrationSpec {
+    pub content_type: String,
+    pub bucket: String,
+    pub env: String,
+    pub to_version: u32,
+    pub steps: Vec<MigrationStep>
+}
+
+/// What the migration does, or would do, to one document
+#[derive(Serialize, Clone, Debug)]
+pub struct MigrationReport {
+    pub id: String,
+    pub version: String,
+    /// a line per step that changed the payload, or "unchanged payload" when none did
+    pub changes: Vec<String>,
+    /// why the document is not migrated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// the UPDATE command sent, not set on a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracking_id: Option<String>,
+    /// why the UPDATE could not be sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing)]
+    payload: Value
+}
+
+impl MigrationStep {
+    /// Apply the step, returns a description when it changed the payload
+    pub fn apply(&self, payload: &mut Map<String, Value>) -> Option<String> {
+        match self {
+            MigrationStep::Rename{from, to} => match payload.remove(from) {
+                Some(value) => {
+                    payload.insert(to.clone(), value);
+                    Some(format!("rename {} to {}", from, to))
+                },
+                None => None
+            },
+            MigrationStep::Default{field, value} => match payload.get(field) {
+                None | Some(Value::Null) => {
+                    payload.insert(field.clone(), value.clone());
+                    Some(format!("default {} to {}", field, value))
+                },
+                Some(_) => None
+            },
+            MigrationStep::Drop{field} => payload.remove(field).map(|_| format!("drop {}", field))
+        }
+    }
+}
+
+impl MigrationSpec {
+
+    /// Reports for every document of the content type that is not at `to_version` yet,
+    /// nothing is sent. Archived documents are only reported, as skipped, with `include_archived`.
+    pub fn plan(&self, lmdb_ctx: &LmdbContext, include_archived: bool) -> Result<Vec<MigrationReport>, MdbError> {
+        let mut reports = Vec::new();
+        let mut seen_ids = HashSet::new();
+        for version in lmdb_ctx.get_log(&self.bucket, &self.env)? {
+            let id = match lmdb_ctx.get_version(&version)? {
+                Some(evt) => String::from(evt.sys.id),
+                None => continue
+            };
+            if !seen_ids.insert(id.clone()) {
+                continue;
+            }
+            let latest = match lmdb_ctx.get_current(&id, include_archived) {
+                Ok(Some(latest)) => latest,
+                Ok(None) | Err(MdbError::NotFound) => continue,
+                Err(err) => return Err(err)
+            };
+            if latest.sys.content_type != self.content_type
+                || latest.sys.content_type_version.map_or(false, |v| v >= self.to_version) {
+                continue;
+            }
+
+            let mut report = MigrationReport {
+                id: id,
+                version: String::from(latest.sys.version),
+                changes: Vec::new(),
+                skipped: None,
+                tracking_id: None,
+                error: None,
+                payload: Value::Null
+            };
+            let state = latest.state();
+            match latest.payload {
+                Some(Value::Object(mut payload)) => {
+                    report.changes = self.steps.iter().filter_map(|step| step.apply(&mut payload)).collect();
+                    // the UPDATE is still sent, it moves the document to `to_version`
+                    if report.changes.is_empty() {
+                        report.changes.push(String::from("unchanged payload"));
+                    }
+                    report.payload = Value::Object(payload);
+                    match state {
+                        DocumentState::Sealed => report.skipped = Some(String::from("sealed")),
+                        DocumentState::Archived => report.skipped = Some(String::from("archived")),
+                        _ => {}
+                    }
+                },
+                Some(_) => report.skipped = Some(String::from("payload is not an object")),
+                None => report.skipped = Some(String::from("deleted"))
+            }
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    /// Send an UPDATE for every planned document, the command worker validates the new payload
+    /// and records the schema version. Send it after the schema itself has been updated.
+    pub fn run(&self, lmdb_ctx: &LmdbContext, kafka_settings: &KafkaSettings, identity: &Identity, include_archived: bool) -> Result<Vec<MigrationReport>, MdbError> {
+        let mut reports = self.plan(lmdb_ctx, include_archived)?;
+        let producer = create_producer(&kafka_settings.brokers);
+        for report in reports.iter_mut().filter(|report| report.skipped.is_none()) {
+            let command = LedgerCommand {
+                tracking_id: "",
+                action: Action::UPDATE(Revision{id: &report.id, version: &report.version}),
+                payload: Some(report.payload.clone()),
+                user_id: None,
+                roles: Vec::new(),
+                patch: None
+            };
+            match produce_command(&producer, &kafka_settings.command_topic, command, identity).wait() {
+                Ok(tracking_id) => report.tracking_id = Some(tracking_id),
+                Err(message) => report.error = Some(message)
+            }
+        }
+        Ok(reports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::fixtures::event;
+    use lmdb_store::temp_context;
+
+    fn spec(steps: Vec<MigrationStep>) -> MigrationSpec {
+        MigrationSpec{content_type: String::from("article"), bucket: String::from("b"), env: String::from("e"), to_version: 2, steps: steps}
+    }
+
+    fn outcomes(reports: Vec<MigrationReport>) -> Vec<(String, Vec<String>, Option<String>)> {
+        reports.into_iter().map(|report| (report.id, report.changes, report.skipped)).collect()
+    }
+
+    #[test]
+    fn steps_describe_what_they_changed() {
+        let mut payload = json!({"title": "a", "legacy": 1, "tags": null}).as_object().unwrap().clone();
+        assert_eq!(MigrationStep::Rename{from: String::from("title"), to: String::from("headline")}.apply(&mut payload), Some(String::from("rename title to headline")));
+        assert_eq!(MigrationStep::Default{field: String::from("tags"), value: json!([])}.apply(&mut payload), Some(String::from("default tags to []")));
+        assert_eq!(MigrationStep::Drop{field: String::from("legacy")}.apply(&mut payload), Some(String::from("drop legacy")));
+        assert_eq!(MigrationStep::Drop{field: String::from("legacy")}.apply(&mut payload), None);
+        assert_eq!(Value::Object(payload), json!({"headline": "a", "tags": []}));
+    }
+
+    #[test]
+    fn plan_covers_documents_below_the_target_version() {
+        let lmdb_ctx = temp_context();
+        lmdb_ctx.set_event(&event("renamed", "v1", DocumentState::Draft, Some(json!({"title": "a"})))).unwrap();
+        lmdb_ctx.set_event(&event("unchanged", "v2", DocumentState::Draft, Some(json!({"headline": "b"})))).unwrap();
+        let mut migrated = event("migrated", "v3", DocumentState::Draft, Some(json!({"title": "c"})));
+        migrated.sys.content_type_version = Some(2);
+        lmdb_ctx.set_event(&migrated).unwrap();
+        lmdb_ctx.set_event(&event("sealed", "v4", DocumentState::Sealed, Some(json!({"title": "d"})))).unwrap();
+        lmdb_ctx.set_event(&event("archived", "v5", DocumentState::Archived, Some(json!({"title": "e"})))).unwrap();
+
+        let rename = spec(vec![MigrationStep::Rename{from: String::from("title"), to: String::from("headline")}]);
+        let renamed = (String::from("renamed"), vec![String::from("rename title to headline")], None);
+        let unchanged = (String::from("unchanged"), vec![String::from("unchanged payload")], None);
+        let sealed = (String::from("sealed"), vec![String::from("rename title to headline")], Some(String::from("sealed")));
+        let archived = (String::from("archived"), vec![String::from("rename title to headline")], Some(String::from("archived")));
+        assert_eq!(outcomes(rename.plan(&lmdb_ctx, false).unwrap()), vec![renamed.clone(), unchanged.clone(), sealed.clone()]);
+        assert_eq!(outcomes(rename.plan(&lmdb_ctx, true).unwrap()), vec![renamed, unchanged, sealed, archived]);
+    }
+}
@@ -0,0 +1,134 @@
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fs;
+
+use config::{ConfigError, PolicySettings};
+
+/// Where a command applies, taken from the command for CREATE and from the document otherwise
+pub struct Scope<'a> {
+    pub bucket: &'a str,
+    pub env: &'a str,
+    pub content_type: &'a str
+}
+
+/// Grants a role some actions, optionally limited to buckets, envs and content types.
+/// An empty list means no limit, `"*"` in `actions` grants every action.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub role: String,
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub buckets: Vec<String>,
+    #[serde(default)]
+    pub envs: Vec<String>,
+    #[serde(default)]
+    pub content_types: Vec<String>
+}
+
+/// Extra roles of users and the rules granted to roles. Whatever no rule grants is denied.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Policy {
+    /// roles by user_id, on top of the roles of their token
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>
+}
+
+fn any_or_contains(allowed: &[String], value: &str) -> bool {
+    allowed.is_empty() || allowed.iter().any(|a| a == value)
+}
+
+impl Rule {
+    fn grants(&self, action: &str, scope: &Scope) -> bool {
+        self.actions.iter().any(|a| a == "*" || a == action)
+            && any_or_contains(&self.buckets, scope.bucket)
+            && any_or_contains(&self.envs, scope.env)
+            && any_or_contains(&self.content_types, scope.content_type)
+    }
+}
+
+impl Policy {
+
+    /// Policy of `policy.file`, `None` when no file is configured and every command is allowed
+    pub fn load(settings: &PolicySettings) -> Result<Option<Policy>, ConfigError> {
+        if settings.file.is_empty() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&settings.file).map_err(ConfigError::IoError)?;
+        Policy::from_toml(&content).map(Some)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Policy, ConfigError> {
+        toml::from_str(content).map_err(ConfigError::ParseError)
+    }
+
+    /// Whether a rule of one of `roles` or of the roles the policy gives `user_id` grants the action
+    pub fn allows(&self, user_id: &str, roles: &[String], action: &str, scope: &Scope) -> bool {
+        let user_roles = self.users.get(user_id).map(|roles| &roles[..]).unwrap_or(&[]);
+        self.rules.iter()
+            .filter(|rule| roles.contains(&rule.role) || user_roles.contains(&rule.role))
+            .any(|rule| rule.grants(action, scope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+[users]
+alice = ["admin"]
+
+[[rule]]
+role = "editor"
+actions = ["CREATE", "UPDATE"]
+buckets = ["content"]
+
+[[rule]]
+role = "admin"
+actions = ["*"]
+"#;
+
+    fn scope<'a>(bucket: &'a str) -> Scope<'a> {
+        Scope{bucket: bucket, env: "prod", content_type: "article"}
+    }
+
+    #[test]
+    fn grants_token_roles() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        let editor = vec![String::from("editor")];
+        assert!(policy.allows("bob", &editor, "UPDATE", &scope("content")));
+        assert!(!policy.allows("bob", &editor, "DELETE", &scope("content")));
+        assert!(!policy.allows("bob", &editor, "UPDATE", &scope("other")));
+    }
+
+    #[test]
+    fn grants_policy_roles_of_user() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        assert!(policy.allows("alice", &[], "DELETE", &scope("other")));
+        assert!(!policy.allows("bob", &[], "CREATE", &scope("content")));
+    }
+
+    #[test]
+    fn rules_are_limited_to_envs_and_content_types() {
+        let policy = Policy::from_toml(r#"
+[[rule]]
+role = "publisher"
+actions = ["PUBLISH"]
+envs = ["staging"]
+content_types = ["article"]
+"#).unwrap();
+        let publisher = vec![String::from("publisher")];
+        assert!(policy.allows("bob", &publisher, "PUBLISH", &Scope{bucket: "b", env: "staging", content_type: "article"}));
+        assert!(!policy.allows("bob", &publisher, "PUBLISH", &Scope{bucket: "b", env: "prod", content_type: "article"}));
+        assert!(!policy.allows("bob", &publisher, "PUBLISH", &Scope{bucket: "b", env: "staging", content_type: "page"}));
+    }
+
+    #[test]
+    fn nothing_is_allowed_without_rules() {
+        let policy = Policy::from_toml("").unwrap();
+        assert!(!policy.allows("alice", &[String::from("admin")], "CREATE", &scope("content")));
+    }
+}
@@ -10,6 +10,7 @@ use self::r2d2_redis::RedisConnectionManager;
 use self::redis::{Commands};
 use domain::{LedgerEvent};
 use kafka::LedgerEvents;
+use config::RedisSettings;
 use serde_json::Value;
 use std::io::{Error, ErrorKind};
 
@@ -28,10 +29,9 @@ type RedisIntResult = Result<i64, RedisContextError>;
 type RedisStringResult = Result<String, RedisContextError>;
 type EventResult<'a> = Result<Box<LedgerEvent<'a, Value>>, Error>;
 
-pub fn new_connection_pool() -> Option<RedisContext> {
-    // TODO: config variablers pool size and redis host
-    let manager = RedisConnectionManager::new("redis://localhost").unwrap();;
-    let pool = r2d2::Pool::builder().max_size(15).build(manager);
+pub fn new_connection_pool(settings: &RedisSettings) -> Option<RedisContext> {
+    let manager = RedisConnectionManager::new(&settings.url[..]).unwrap();
+    let pool = r2d2::Pool::builder().max_size(settings.pool_size).build(manager);
 
     let context : Option<RedisContext> = match pool {
         Ok(p) => Some(RedisContext{pool:p}), 
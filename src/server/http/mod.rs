@@ -0,0 +1,373 @@
+extern crate hyper;
+extern crate futures;
+extern crate serde_json;
+extern crate uuid;
+extern crate rdkafka;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use self::hyper::{Body, Method, Request, Response, Server, StatusCode};
+use self::hyper::service::service_fn;
+use self::hyper::rt::{self, Future, Stream};
+use self::futures::future;
+use self::futures::sync::oneshot;
+use self::uuid::Uuid;
+use self::rdkafka::producer::FutureProducer;
+
+use auth::{bearer_token, AuthError, Authenticator, Identity};
+use config::Settings;
+use domain::diff::DiffError;
+use domain::{Action, CommandResult, ErrorCode, LedgerCommand, LedgerEvent, PayloadPatch, Revision};
+use kafka::producer::{create_producer, produce_command};
+use kafka::reply::ResultListener;
+use lmdb_rs::core::MdbError;
+use lmdb_store::{create_context, LmdbContext};
+use serde_json::Value;
+
+type ResponseFuture = Box<Future<Item=Response<Body>, Error=hyper::Error> + Send>;
+
+pub struct HttpContext {
+    pub settings: Settings,
+    pub lmdb_ctx: LmdbContext,
+    pub results: ResultListener,
+    /// shared by all requests, commands are sent on it
+    pub producer: FutureProducer,
+    pub authenticator: Box<Authenticator>
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    category: String,
+    content_type: String,
+    bucket: String,
+    env: String,
+    payload: Option<Value>
+}
+
+#[derive(Deserialize)]
+struct RevisionRequest {
+    version: String,
+    payload: Option<Value>,
+    /// instead of the payload on an update
+    #[serde(default)]
+    patch: Option<PayloadPatch>,
+    /// earlier version whose payload a revert restores
+    #[serde(default)]
+    to_version: Option<String>
+}
+
+/// HTTP status for a rejected command
+pub fn status_for(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::OptimisticLock => StatusCode::CONFLICT,
+        ErrorCode::Sealed => StatusCode::CONFLICT,
+        ErrorCode::AlreadyExists => StatusCode::CONFLICT,
+        ErrorCode::NotPublished => StatusCode::CONFLICT,
+        ErrorCode::InvalidState => StatusCode::CONFLICT,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::IdMismatch => StatusCode::BAD_REQUEST,
+        ErrorCode::InvalidCommand => StatusCode::BAD_REQUEST,
+        ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::SchemaViolation => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::StoreError => StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, json!({"error": message}).to_string())
+}
+
+fn event_response(result: Result<Option<LedgerEvent<Value>>, MdbError>) -> Response<Body> {
+    match result {
+        Ok(Some(evt)) => match serde_json::to_string(&evt) {
+            Ok(json_str) => json_response(StatusCode::OK, json_str),
+            Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+        },
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Document not found"),
+        Err(MdbError::NotFound) => error_response(StatusCode::NOT_FOUND, "Document not found"),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query().and_then(|query| query.split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None
+            }
+        })
+        .next())
+}
+
+fn get_document(ctx: &HttpContext, id: &str, include_archived: bool) -> Response<Body> {
+    event_response(ctx.lmdb_ctx.get_current(id, include_archived))
+}
+
+/// Latest version of a document the request may read, or the response when it may not
+fn current_document<'a>(ctx: &'a HttpContext, id: &str, include_archived: bool) -> Result<LedgerEvent<'a, Value>, Response<Body>> {
+    match ctx.lmdb_ctx.get_current(id, include_archived) {
+        Ok(Some(latest)) => Ok(latest),
+        Ok(None) | Err(MdbError::NotFound) => Err(error_response(StatusCode::NOT_FOUND, "Document not found")),
+        Err(err) => Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err)))
+    }
+}
+
+fn get_document_version(ctx: &HttpContext, id: &str, version: &str, include_archived: bool) -> Response<Body> {
+    if let Err(response) = current_document(ctx, id, include_archived) {
+        return response;
+    }
+    match ctx.lmdb_ctx.get_version(version) {
+        Ok(Some(ref evt)) if evt.sys.id != id => error_response(StatusCode::NOT_FOUND, "Version not found"),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Version not found"),
+        result => event_response(result)
+    }
+}
+
+fn get_history(ctx: &HttpContext, id: &str, limit: u8, include_archived: bool) -> Response<Body> {
+    let latest = match current_document(ctx, id, include_archived) {
+        Ok(latest) => latest,
+        Err(response) => return response
+    };
+    match ctx.lmdb_ctx.get_previous(latest.sys.version, limit) {
+        Ok(history) => json_response(StatusCode::OK, json!(history).to_string()),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+fn get_diff(ctx: &HttpContext, id: &str, from: Option<&str>, to: Option<&str>, include_archived: bool) -> Response<Body> {
+    match ctx.lmdb_ctx.diff_versions(id, from, to, include_archived) {
+        Ok(Ok(diff)) => json_response(StatusCode::OK, json!(diff).to_string()),
+        Ok(Err(DiffError::FirstVersion{..})) => error_response(StatusCode::BAD_REQUEST, "No version to compare with, give from"),
+        Ok(Err(diff_error)) => error_response(StatusCode::NOT_FOUND, &format!("{}", diff_error)),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+/// Verify the hash chain of a document from its latest version back to its first
+fn verify_document(ctx: &HttpContext, id: &str, include_archived: bool) -> Response<Body> {
+    match ctx.lmdb_ctx.verify_history(id, include_archived) {
+        Ok(Ok(count)) => json_response(StatusCode::OK, json!({"id": id, "verified_versions": count}).to_string()),
+        Ok(Err(chain_error)) => json_response(StatusCode::CONFLICT, json!({"id": id, "error": format!("{}", chain_error), "chain_error": chain_error}).to_string()),
+        Err(MdbError::NotFound) => error_response(StatusCode::NOT_FOUND, "Document not found"),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+fn root_sequence(req: &Request<Body>, name: &str) -> Result<Option<u64>, Response<Body>> {
+    match query_param(req, name) {
+        Some(sequence) => sequence.parse().map(Some)
+            .map_err(|_| error_response(StatusCode::BAD_REQUEST, &format!("{} must be a root sequence number", name))),
+        None => Ok(None)
+    }
+}
+
+/// Sealed merkle root of a bucket/env, the latest one unless a sequence is given
+fn get_merkle_root(ctx: &HttpContext, bucket: &str, env: &str, sequence: Option<u64>) -> Response<Body> {
+    match ctx.lmdb_ctx.get_merkle_root(bucket, env, sequence) {
+        Ok(Some(root)) => json_response(StatusCode::OK, json!(root).to_string()),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Root not found"),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+/// Inclusion proof of a version against a sealed root, the latest one unless a sequence is given
+fn get_merkle_proof(ctx: &HttpContext, id: &str, version: &str, sequence: Option<u64>, include_archived: bool) -> Response<Body> {
+    if let Err(response) = current_document(ctx, id, include_archived) {
+        return response;
+    }
+    match ctx.lmdb_ctx.get_version(version) {
+        Ok(Some(ref evt)) if evt.sys.id == id => {},
+        Ok(_) => return error_response(StatusCode::NOT_FOUND, "Version not found"),
+        Err(err) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+    match ctx.lmdb_ctx.merkle_proof(version, sequence) {
+        Ok(Some(proof)) => json_response(StatusCode::OK, json!(proof).to_string()),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Version is not sealed under this root"),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+    }
+}
+
+/// Produce the command and answer with its result, or 202 when it does not arrive in time.
+/// A command the brokers did not take is answered with 503.
+fn send_command(ctx: &Arc<HttpContext>, command: LedgerCommand<Value>, identity: &Identity, success: StatusCode) -> ResponseFuture {
+    let tracking_id = Uuid::new_v4().to_hyphenated().to_string();
+    let (result_in, result_out) = oneshot::channel::<Option<String>>();
+    ctx.results.register(&tracking_id, move |result| {
+        let _ = result_in.send(result);
+    });
+    let delivery = produce_command(&ctx.producer, &ctx.settings.kafka.command_topic, LedgerCommand{tracking_id: &tracking_id, ..command}, identity);
+
+    let ctx = ctx.clone();
+    Box::new(delivery.then(move |delivered| -> ResponseFuture {
+        if let Err(message) = delivered {
+            error!("{}", message);
+            ctx.results.unregister(&tracking_id);
+            return Box::new(future::ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "Command could not be sent, retry later")));
+        }
+        Box::new(result_out.then(move |received| {
+            let response = match received {
+                Ok(Some(result_json)) => match serde_json::from_str::<CommandResult>(&result_json) {
+                    Ok(CommandResult::Accepted{..}) => json_response(success, result_json.clone()),
+                    Ok(CommandResult::Rejected{ref error, ..}) => json_response(status_for(error.code), result_json.clone()),
+                    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err))
+                },
+                _ => json_response(StatusCode::ACCEPTED, json!({"tracking_id": tracking_id}).to_string())
+            };
+            Ok::<_, hyper::Error>(response)
+        }))
+    }))
+}
+
+fn create_document(ctx: &Arc<HttpContext>, body: &[u8], identity: &Identity) -> ResponseFuture {
+    match serde_json::from_slice::<CreateRequest>(body) {
+        Ok(request) => {
+            let command = LedgerCommand {
+                tracking_id: "",
+                action: Action::CREATE {
+                    category: &request.category,
+                    content_type: &request.content_type,
+                    bucket: &request.bucket,
+                    env: &request.env
+                },
+                payload: request.payload.clone(),
+                user_id: None,
+                roles: Vec::new(),
+                patch: None
+            };
+            send_command(ctx, command, identity, StatusCode::CREATED)
+        },
+        Err(err) => Box::new(future::ok(error_response(StatusCode::BAD_REQUEST, &format!("{}", err))))
+    }
+}
+
+fn change_document(ctx: &Arc<HttpContext>, id: &str, action_name: &str, body: &[u8], identity: &Identity) -> ResponseFuture {
+    let request = match serde_json::from_slice::<RevisionRequest>(body) {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::ok(error_response(StatusCode::BAD_REQUEST, &format!("{}", err))))
+    };
+    let revision = Revision{id: id, version: &request.version};
+    let action = match action_name {
+        "update" => Action::UPDATE(revision),
+        "delete" => Action::DELETE(revision),
+        "copy" => Action::COPY(revision),
+        "seal" => Action::SEAL(revision),
+        "publish" => Action::PUBLISH(revision),
+        "unpublish" => Action::UNPUBLISH(revision),
+        "archive" => Action::ARCHIVE(revision),
+        "unarchive" => Action::UNARCHIVE(revision),
+        "revert" => match request.to_version {
+            Some(ref to_version) => Action::REVERT{id: id, version: &request.version, to_version: to_version},
+            None => return Box::new(future::ok(error_response(StatusCode::BAD_REQUEST, "to_version is required to revert")))
+        },
+        _ => return Box::new(future::ok(error_response(StatusCode::NOT_FOUND, "Unknown action")))
+    };
+    let command = LedgerCommand {
+        tracking_id: "",
+        action: action,
+        payload: request.payload.clone(),
+        user_id: None,
+        roles: Vec::new(),
+        patch: request.patch.clone()
+    };
+    send_command(ctx, command, identity, StatusCode::OK)
+}
+
+fn handle(ctx: Arc<HttpContext>, req: Request<Body>) -> ResponseFuture {
+    let path : Vec<String> = req.uri().path().trim_matches('/').split('/').map(String::from).collect();
+    let segments : Vec<&str> = path.iter().map(|segment| &segment[..]).collect();
+    let identity = req.headers().get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token)
+        .ok_or(AuthError::MissingToken)
+        .and_then(|token| ctx.authenticator.authenticate(token));
+
+    // reads need a token as much as commands do
+    if let Err(err) = identity {
+        return Box::new(future::ok(error_response(StatusCode::UNAUTHORIZED, &format!("{}", err))));
+    }
+    let identity = identity.unwrap();
+    // every read of a document leaves archived ones out unless they are asked for
+    let include_archived = query_param(&req, "include_archived") == Some("true");
+
+    match (req.method(), &segments[..]) {
+        (&Method::GET, ["documents", id]) => Box::new(future::ok(get_document(&ctx, id, include_archived))),
+        (&Method::GET, ["documents", id, "history"]) => {
+            let limit = query_param(&req, "limit").and_then(|limit| limit.parse().ok()).unwrap_or(10);
+            Box::new(future::ok(get_history(&ctx, id, limit, include_archived)))
+        },
+        (&Method::GET, ["documents", id, "diff"]) =>
+            Box::new(future::ok(get_diff(&ctx, id, query_param(&req, "from"), query_param(&req, "to"), include_archived))),
+        (&Method::GET, ["documents", id, "verify"]) => Box::new(future::ok(verify_document(&ctx, id, include_archived))),
+        (&Method::GET, ["documents", id, "versions", version, "proof"]) => match root_sequence(&req, "root") {
+            Ok(sequence) => Box::new(future::ok(get_merkle_proof(&ctx, id, version, sequence, include_archived))),
+            Err(response) => Box::new(future::ok(response))
+        },
+        (&Method::GET, ["merkle", bucket, env, "root"]) => match root_sequence(&req, "sequence") {
+            Ok(sequence) => Box::new(future::ok(get_merkle_root(&ctx, bucket, env, sequence))),
+            Err(response) => Box::new(future::ok(response))
+        },
+        (&Method::GET, ["documents", id, "versions", version]) => Box::new(future::ok(get_document_version(&ctx, id, version, include_archived))),
+        (&Method::POST, ["documents"]) => {
+            Box::new(req.into_body().concat2().and_then(move |body| create_document(&ctx, &body, &identity)))
+        },
+        (&Method::POST, ["documents", id, action]) => {
+            let id = String::from(*id);
+            let action = String::from(*action);
+            Box::new(req.into_body().concat2().and_then(move |body| change_document(&ctx, &id, &action, &body, &identity)))
+        },
+        _ => Box::new(future::ok(error_response(StatusCode::NOT_FOUND, "Not found")))
+    }
+}
+
+/// Serve the REST API, blocks the calling thread
+pub fn start_server(settings: &Settings, authenticator: Box<Authenticator>) {
+    let addr = settings.http.listen.parse().expect("Invalid http listen address");
+    let ctx = Arc::new(HttpContext {
+        settings: settings.clone(),
+        lmdb_ctx: create_context(&settings.lmdb).expect("Could not open LMDB store"),
+        results: ResultListener::start(&settings.kafka, Duration::from_millis(settings.http.result_timeout_ms)),
+        producer: create_producer(&settings.kafka.brokers),
+        authenticator: authenticator
+    });
+
+    let server = Server::bind(&addr)
+        .serve(move || {
+            let ctx = ctx.clone();
+            service_fn(move |req| handle(ctx.clone(), req))
+        })
+        .map_err(|err| error!("Http server error: {}", err));
+
+    info!("Http server listening on {}", addr);
+    rt::run(server);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_documents_are_not_found() {
+        assert_eq!(event_response(Ok(None)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(event_response(Err(MdbError::NotFound)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(event_response(Err(MdbError::Corrupted)).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn query_params_are_found_by_name() {
+        let req = Request::get("/documents/doc/history?limit=5&root=2").body(Body::empty()).unwrap();
+        assert_eq!(query_param(&req, "root"), Some("2"));
+        assert_eq!(query_param(&req, "limit"), Some("5"));
+        assert_eq!(query_param(&req, "lim"), None);
+    }
+}
@@ -1 +1,2 @@
-pub mod ws;
\ No newline at end of file
+pub mod http;
+pub mod ws;
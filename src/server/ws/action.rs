@@ -1,28 +1,116 @@
 extern crate ws;
 
 use ws::{Sender as WsSender};
-use domain::SubscriptionEvent;
+use auth::Identity;
+use domain::{LedgerEvent, SubscriptionEvent};
+use serde_json::Value;
 
 
 pub enum WsClientAction {
   Open{conn_id: String, sender: WsSender},
-  Subscribe{conn_id: String, ws_topic:String},
+  Subscribe{conn_id: String, ws_topic:String, since: Option<String>, include_archived: bool},
   Unsubscribe{conn_id: String, ws_topic: String},
-  // Command(LedgerCommand) typ
+  /// `command` is the JSON of a `LedgerCommand`, already checked to parse
+  Command{conn_id: String, identity: Identity, command: String},
   Close{conn_id: String}
 }
 
+/// What a client can subscribe to, sent as e.g. `{"content_type": "article"}`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WsTopic {
+  Id(String),
+  ContentType(String),
+  Bucket(String),
+  Env(String)
+}
+
+/// JSON frames sent by clients, e.g. `{"type": "subscribe", "topic": {"id": "..."}}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsClientMessage {
+  /// with `since` the events stored after that version are sent first. Events of archived
+  /// documents are left out unless `include_archived` is set
+  Subscribe{
+    topic: WsTopic,
+    #[serde(default)]
+    since: Option<String>,
+    #[serde(default)]
+    include_archived: bool
+  },
+  Unsubscribe{topic: WsTopic},
+  /// `command` is a `LedgerCommand`, the server gives it a tracking id of its own and sends it
+  /// back with the result, together with the client's as `client_tracking_id`
+  Command{command: Value},
+  Close
+}
+
+impl WsTopic {
+  /// Key of the topic in the subscriber registry and on the subscriber stream
+  pub fn key(&self) -> String {
+    match self {
+      WsTopic::Id(id) => format!("id:{}", id),
+      WsTopic::ContentType(content_type) => format!("content_type:{}", content_type),
+      WsTopic::Bucket(bucket) => format!("bucket:{}", bucket),
+      WsTopic::Env(env) => format!("env:{}", env)
+    }
+  }
+}
+
+/// Keys of every topic an event is delivered on
+pub fn event_topic_keys(event: &LedgerEvent<Value>) -> Vec<String> {
+  vec![
+    WsTopic::Id(String::from(event.sys.id)).key(),
+    WsTopic::ContentType(String::from(event.sys.content_type)).key(),
+    WsTopic::Bucket(String::from(event.sys.bucket)).key(),
+    WsTopic::Env(String::from(event.sys.env)).key()
+  ]
+}
+
 impl WsClientAction {
-  pub fn to_subscription_event(&self) -> SubscriptionEvent {
+  /// `None` for actions that are not published on the subscriber stream
+  pub fn to_subscription_event(&self) -> Option<SubscriptionEvent> {
     match self {
-      WsClientAction::Open{conn_id, sender:_ } => 
-        SubscriptionEvent::Open{conn_id: &conn_id},
-      WsClientAction::Subscribe{conn_id, ws_topic} => 
-        SubscriptionEvent::Subscribe{conn_id: &conn_id, topic: &ws_topic},
-      WsClientAction::Unsubscribe{conn_id, ws_topic} => 
-        SubscriptionEvent::Unsubscribe{conn_id: &conn_id, topic: &ws_topic},
-      WsClientAction::Close{conn_id} => 
-        SubscriptionEvent::Open{conn_id: &conn_id},
+      WsClientAction::Open{conn_id, sender:_ } =>
+        Some(SubscriptionEvent::Open{conn_id: &conn_id}),
+      WsClientAction::Subscribe{conn_id, ws_topic, since, include_archived} =>
+        Some(SubscriptionEvent::Subscribe{conn_id: &conn_id, topic: &ws_topic, since: since.as_ref().map(String::as_str), include_archived: *include_archived}),
+      WsClientAction::Unsubscribe{conn_id, ws_topic} =>
+        Some(SubscriptionEvent::Unsubscribe{conn_id: &conn_id, topic: &ws_topic}),
+      WsClientAction::Command{..} => None,
+      WsClientAction::Close{conn_id} =>
+        Some(SubscriptionEvent::Close{conn_id: &conn_id}),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use domain::DocumentState;
+  use domain::fixtures::event;
+
+  #[test]
+  fn client_messages_name_a_topic() {
+    match serde_json::from_str::<WsClientMessage>(r#"{"type": "subscribe", "topic": {"content_type": "article"}}"#).unwrap() {
+      WsClientMessage::Subscribe{topic, ..} => assert_eq!(topic.key(), "content_type:article"),
+      other => panic!("expected a subscribe, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn events_are_delivered_on_their_id_content_type_bucket_and_env() {
+    assert_eq!(event_topic_keys(&event("doc", "v1", DocumentState::Draft, None)), vec!["id:doc", "content_type:article", "bucket:b", "env:e"]);
+  }
+
+  #[test]
+  fn commands_are_not_published_as_subscription_events() {
+    let message = r#"{"type": "command", "command": {"tracking_id": "mine", "action": {"type": "DELETE", "id": "doc", "version": "v1"}, "payload": null, "user_id": null}}"#;
+    match serde_json::from_str::<WsClientMessage>(message).unwrap() {
+      WsClientMessage::Command{command} => assert_eq!(command["tracking_id"], json!("mine")),
+      other => panic!("expected a command, got {:?}", other)
+    }
+    let action = WsClientAction::Command{conn_id: String::from("c"), identity: Identity{user_id: String::from("user"), roles: vec![]}, command: String::from("{}")};
+    assert!(action.to_subscription_event().is_none());
+  }
+}
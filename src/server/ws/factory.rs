@@ -2,14 +2,17 @@ extern crate uuid;
 extern crate ws;
 
 use ws::{Sender as WsSender, Factory};
+use std::sync::Arc;
 use std::sync::mpsc::{Sender};
 
 use server::ws::handler::WsHandler;
 use server::ws::action::WsClientAction;
 use self::uuid::Uuid;
+use auth::Authenticator;
 
 pub struct WsFactory {
-  pub sender: Sender<WsClientAction>
+  pub sender: Sender<WsClientAction>,
+  pub authenticator: Arc<Authenticator>
 }
 
 impl Factory for WsFactory {
@@ -20,6 +23,8 @@ impl Factory for WsFactory {
       WsHandler {
         out: ws,
         conn_id: Uuid::new_v4().to_hyphenated().to_string(),
+        identity: None,
+        authenticator: self.authenticator.clone(),
         // default to server
         config_event: Some(self.sender.clone()),
       }
@@ -31,6 +36,8 @@ impl Factory for WsFactory {
       WsHandler {
         out: ws,
         conn_id: Uuid::new_v4().to_hyphenated().to_string(),
+        identity: None,
+        authenticator: self.authenticator.clone(),
         config_event: None,
       }
     }
@@ -41,6 +48,8 @@ impl Factory for WsFactory {
       WsHandler {
         out: ws,
         conn_id: Uuid::new_v4().to_hyphenated().to_string(),
+        identity: None,
+        authenticator: self.authenticator.clone(),
         config_event: Some(self.sender.clone()),
       }
     }
@@ -2,21 +2,83 @@
 extern crate ws;
 
 use ws::{Sender as WsSender, Handler, Result, Message, Handshake, CloseCode, Error};
+use std::sync::Arc;
 use std::sync::mpsc::{Sender};
 
-use server::ws::action::WsClientAction;
+use server::ws::action::{WsClientAction, WsClientMessage};
+use auth::{bearer_token, query_token, AuthError, Authenticator, Identity};
+use domain::LedgerCommand;
+use serde_json::Value;
 
 pub struct WsHandler {
     pub out: WsSender,
     pub conn_id: String,
+    /// set once the handshake is authenticated, recorded on the commands of this connection
+    pub identity: Option<Identity>,
+    pub authenticator: Arc<Authenticator>,
     pub config_event: Option<Sender<WsClientAction>>
 }
 
+impl WsHandler {
+
+    fn send_action(&self, action: WsClientAction) {
+      match &self.config_event {
+        Some(sender) => {
+          if let Err(err) = sender.send(action) {
+            error!("WsClientAction send Error {}", err)
+          }
+        },
+        None => debug!("No sender for {}", self.conn_id)
+      }
+    }
+
+    /// Identity for the token of an `Authorization: Bearer` header, or of a `token` query
+    /// parameter since browsers can't set headers on a WebSocket
+    fn authenticate(&self, handshake: &Handshake) -> std::result::Result<Identity, AuthError> {
+      let header_token = handshake.request.header("authorization")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(bearer_token)
+        .map(String::from);
+      let query_token = handshake.request.resource().splitn(2, '?').nth(1)
+        .and_then(query_token);
+      match header_token.or(query_token) {
+        Some(token) => self.authenticator.authenticate(&token),
+        None => Err(AuthError::MissingToken)
+      }
+    }
+
+    fn send_command(&self, identity: &Identity, command: Value) -> Result<()> {
+      let command_json = command.to_string();
+      match serde_json::from_str::<LedgerCommand<Value>>(&command_json) {
+        Ok(_) => {
+          self.send_action(WsClientAction::Command{conn_id: self.conn_id.clone(), identity: identity.clone(), command: command_json.clone()});
+          Ok(())
+        },
+        Err(err) => self.out.send(json!({"type": "error", "message": format!("Invalid command: {}", err)}).to_string())
+      }
+    }
+
+    fn close(&self) -> Result<()> {
+      match self.out.close(CloseCode::Normal) {
+        Ok(_) => info!("Client requested close. Closed."),
+        Err(err) => error!("Client requested close Error {}", err)
+      };
+      Ok(())
+    }
+}
+
 impl Handler for WsHandler {
 
-    fn on_open(&mut self, _: Handshake) -> Result<()> {
+    fn on_open(&mut self, handshake: Handshake) -> Result<()> {
       info!("Ws connection is OPENed");
-      
+      match self.authenticate(&handshake) {
+        Ok(identity) => self.identity = Some(identity),
+        Err(err) => {
+          info!("Rejected connection {}: {}", self.conn_id, err);
+          return self.out.close_with_reason(CloseCode::Policy, format!("{}", err));
+        }
+      };
+
         match &self.config_event {
           Some(sender) => {
             match sender.send(WsClientAction::Open{conn_id: self.conn_id.clone(), sender: self.out.clone()}) {
@@ -36,72 +98,32 @@ impl Handler for WsHandler {
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
         match msg {
+          // nothing is accepted from a connection that did not authenticate
+          Message::Text(_) if self.identity.is_none() => Ok(()),
           Message::Text(text) => {
             trace!("The message is text {}", text);
-            match text.as_str() {
-              
-              "sub" => {
-                self.out.send("ping")?;
-
-                match &self.config_event {
-                  Some(sender) => {
-                    match sender.send(WsClientAction::Subscribe{conn_id: self.conn_id.clone(), ws_topic: text.clone()}) {
-                        Ok(_) => trace!("WsClientAction send sub ok"),
-                        Err(err) => {
-                          error!("WsClientAction send sub Error {}", err)
-                        }
-                    };
-                    Ok(())
-                  },
-                  None => {
-                    debug!("No sender for {}", self.conn_id);
-                    Ok(())
-                  }
-                }
+            match serde_json::from_str::<WsClientMessage>(&text) {
+              Ok(WsClientMessage::Subscribe{topic, since, include_archived}) => {
+                self.send_action(WsClientAction::Subscribe{conn_id: self.conn_id.clone(), ws_topic: topic.key(), since: since, include_archived: include_archived});
+                self.out.send(json!({"type": "subscribed", "topic": topic.key()}).to_string())
               },
-              "unsub" => {
-                match &self.config_event {
-                  Some(sender) => {
-                    match sender.send(WsClientAction::Unsubscribe{conn_id: self.conn_id.clone(), ws_topic: text.clone()}) {
-                        Ok(_) => trace!("WsClientAction send unsub ok"),
-                        Err(err) => {
-                          error!("WsClientAction send unsub Error {}", err)
-                        }
-                    };
-                    Ok(())
-                  },
-                  None => {
-                    debug!("No sender for {}", self.conn_id);
-                    Ok(())
-                  }
-                }
-              },
-              "pong" => {
-                 match self.out.close(CloseCode::Normal)
-                         {
-                            Ok(_) => info!("Client requested close. Closed."),
-                            Err(err) => {
-                              error!("Client requested close Error {}", err)
-                            }
-                          };
-                          Ok(())
+              Ok(WsClientMessage::Unsubscribe{topic}) => {
+                self.send_action(WsClientAction::Unsubscribe{conn_id: self.conn_id.clone(), ws_topic: topic.key()});
+                self.out.send(json!({"type": "unsubscribed", "topic": topic.key()}).to_string())
               },
-              "close" => {
-                 match self.out.close(CloseCode::Normal)
-                         {
-                            Ok(_) => info!("Client requested close. Closed."),
-                            Err(err) => {
-                              error!("Client requested close Error {}", err)
-                            }
-                          };
-                          Ok(())
+              Ok(WsClientMessage::Command{command}) => match self.identity {
+                Some(ref identity) => self.send_command(identity, command),
+                None => Ok(())
               },
-              _ => {
-                info!("Unknown command {}", text);
-                Ok(())
+              Ok(WsClientMessage::Close) => self.close(),
+              Err(err) => match text.as_str() {
+                "pong" | "close" => self.close(),
+                _ => {
+                  info!("Unknown command {} err={}", text, err);
+                  self.out.send(json!({"type": "error", "message": format!("{}", err)}).to_string())
+                }
               }
             }
-            
           }, 
           _ => core::result::Result::Ok(())
         }
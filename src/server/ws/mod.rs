@@ -1,53 +1,95 @@
 extern crate rdkafka;
+extern crate uuid;
 
 use std::sync::mpsc::{channel, Sender, Receiver};
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 use std::rc::Rc;
 
 use ws::{Sender as WsSender};
-use ws::{Builder, Settings};
+use ws::{Builder, Settings as WsBuilderSettings};
 use std::thread;
 
-use self::rdkafka::producer::{FutureProducer, FutureRecord};
+use self::rdkafka::producer::FutureProducer;
+use futures::Future;
+use self::uuid::Uuid;
 
-use kafka::producer::{create_producer, produce_subscription_event};
+use kafka::producer::{create_producer, produce_command, produce_subscription_event};
+use kafka::reply::ResultListener;
 use kafka::{LedgerEvents, LedgerEventsConsumer};
-use kafka::KAFKA_EVT_SUBSCRIBERS_CONFIG;
-use domain::{LedgerEvent, SubscriptionEvent};
+use auth::{Authenticator, Identity};
+use config::{KafkaSettings, Settings, WsSettings};
+use domain::{DocumentState, LedgerCommand, LedgerEvent, SubscriptionEvent};
+use lmdb_store::{create_context, LmdbContext};
 use serde_json::Value;
 
 pub mod action;
 pub mod handler;
 pub mod factory;
 
-use self::action::WsClientAction;
+use self::action::{event_topic_keys, WsClientAction};
 use self::factory::WsFactory;
 
 pub struct WsContext {
+    pub settings: WsSettings,
     pub client_events_in: Sender<WsClientAction>,
-    pub clients : HashMap<String, WsSender>,
-    pub subscribers: HashMap<String, HashSet<String>>
+    /// connections of this server by conn_id, filled as they open
+    pub clients : Arc<Mutex<HashMap<String, WsSender>>>,
+    /// conn_ids by topic key, filled from the subscriber stream
+    pub subscribers: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    /// sequence up to which events were replayed, by conn_id and topic key
+    pub replayed: Mutex<HashMap<(String, String), u64>>,
+    /// conn_id and topic key of the subscriptions that also get events of archived documents
+    pub include_archived: Mutex<HashSet<(String, String)>>,
+    /// has to be added as events hook before the context itself, replays are read from it
+    pub lmdb_ctx: Arc<LmdbContext>,
+    pub authenticator: Arc<Authenticator>
 }
 
 
 impl WsContext {
 
-    pub fn new() -> WsContext {
+    pub fn new(settings: &Settings, authenticator: Box<Authenticator>) -> WsContext {
     let (client_events_in, client_events_out) : (Sender<WsClientAction>, Receiver<WsClientAction>) = channel();
 
+        let clients : Arc<Mutex<HashMap<String, WsSender>>> = Arc::new(Mutex::new(HashMap::new()));
+
 // Receive events from clients from a mpsc channel in a thread 
+        let kafka_settings = settings.kafka.clone();
+        let thread_clients = clients.clone();
+        let results = ResultListener::start(&settings.kafka, Duration::from_millis(settings.ws.result_timeout_ms));
         let client_events_thread = thread::Builder::new()
             .name("logger".to_owned())
             .spawn(move || {
             info!("EVENTCHANNEL start listen");
 
-            let producer = create_producer(KAFKA_EVT_SUBSCRIBERS_CONFIG.brokers);
+            let producer = create_producer(&kafka_settings.brokers);
 
             while let Ok(cfg_evt) = client_events_out.recv() {
-                produce_subscription_event(&producer, cfg_evt.to_subscription_event());
+                // senders can't go through kafka, keep them here
+                match &cfg_evt {
+                    WsClientAction::Open{conn_id, sender} => {
+                        thread_clients.lock().unwrap().insert(conn_id.clone(), sender.clone());
+                    },
+                    WsClientAction::Close{conn_id} => {
+                        thread_clients.lock().unwrap().remove(conn_id);
+                    },
+                    WsClientAction::Command{conn_id, identity, command} => {
+                        let ws_sender = thread_clients.lock().unwrap().get(conn_id).cloned();
+                        match ws_sender {
+                            Some(ws_sender) => send_command(&producer, &kafka_settings, &results, ws_sender, command, identity),
+                            None => warn!("Command from unknown conn_id {}", conn_id)
+                        }
+                    },
+                    _ => {}
+                }
+                if let Some(subscription_event) = cfg_evt.to_subscription_event() {
+                    produce_subscription_event(&producer, &kafka_settings.subscriber_topic, subscription_event);
+                }
             }
 
             info!("Logger sending final message.");
@@ -55,42 +97,174 @@ impl WsContext {
             .unwrap();
 
         WsContext {
+            settings: settings.ws.clone(),
             client_events_in: client_events_in.clone(),
-            clients: HashMap::new(),
-            subscribers: HashMap::new()
+            clients: clients,
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            replayed: Mutex::new(HashMap::new()),
+            include_archived: Mutex::new(HashSet::new()),
+            lmdb_ctx: Arc::new(create_context(&settings.lmdb).expect("Could not open LMDB store")),
+            authenticator: Arc::from(authenticator)
         }
     }
 
-    pub fn start_server(&self) {
-        Builder::new()
-            .with_settings(Settings {
-                max_connections: 28232,
-                ..Settings::default()
+    /// Listen for WebSocket connections in a thread of its own
+    pub fn start_server(&self) -> JoinHandle<()> {
+        let settings = self.settings.clone();
+        let factory = WsFactory{sender: self.client_events_in.clone(), authenticator: self.authenticator.clone()};
+        thread::Builder::new()
+            .name("ws-server".to_owned())
+            .spawn(move || {
+                Builder::new()
+                    .with_settings(WsBuilderSettings {
+                        max_connections: settings.max_connections,
+                        ..WsBuilderSettings::default()
+                    })
+                    .build(factory)
+                    .unwrap()
+                    .listen(&settings.listen[..])
+                    .unwrap();
             })
-            .build(WsFactory{sender: self.client_events_in.clone()})
             .unwrap()
-            .listen("127.0.0.1:3012")
-            .unwrap();
-
-        //let _ = self.client_events_thread.join();
-
     }
 
+    /// Send the event to every connection subscribed to one of its topics,
+    /// except those that already got it replayed. Events that archive a document only go to
+    /// subscriptions that include archived documents.
     pub fn send_event(&self, event:&LedgerEvent<Value>) -> Result<(), ws::Error> {
-        for (client_id, ws_sender) in &self.clients {
-            match serde_json::to_string(event) {
-                Result::Ok(val) => {
-                    ws_sender.send(val);
+        let topics = event_topic_keys(event);
+        let archived = event.state() == DocumentState::Archived;
+        let conn_ids : HashSet<String> = {
+            let subscribers = self.subscribers.lock().unwrap();
+            let include_archived = self.include_archived.lock().unwrap();
+            topics.iter()
+                .filter_map(|topic| subscribers.get(topic).map(|topic_conn_ids| (topic, topic_conn_ids)))
+                .flat_map(|(topic, topic_conn_ids)| topic_conn_ids.iter()
+                    .filter(|conn_id| !archived || include_archived.contains(&((*conn_id).clone(), topic.clone())))
+                    .cloned()
+                    .collect::<Vec<String>>())
+                .collect()
+        };
+        if conn_ids.is_empty() {
+            return Ok(());
+        }
+
+        let replayed = self.replayed.lock().unwrap();
+        let event_sequence = if replayed.is_empty() {
+            None
+        } else {
+            self.lmdb_ctx.get_sequence(event.sys.version).unwrap_or(None)
+        };
+        let replayed_up_to = |conn_id: &str| topics.iter()
+            .filter_map(|topic| replayed.get(&(String::from(conn_id), topic.clone())))
+            .max()
+            .cloned();
+
+        match serde_json::to_string(event) {
+            Result::Ok(val) => {
+                let clients = self.clients.lock().unwrap();
+                for conn_id in &conn_ids {
+                    if let (Some(sequence), Some(up_to)) = (event_sequence, replayed_up_to(conn_id.as_str())) {
+                        if sequence <= up_to {
+                            continue;
+                        }
+                    }
+                    if let Some(ws_sender) = clients.get(conn_id) {
+                        if let Err(err) = ws_sender.send(val.as_str()) {
+                            warn!("Error while sending event {} to conn_id {} err={}", event.event_id, conn_id, err);
+                        }
+                    }
                 }
-                Result::Err(err) => {
-                    print!("Error in serialization {}", err);
+            }
+            Result::Err(err) => {
+                error!("Error in serialization {}", err);
+            }
+        };
+        Ok(())
+    }
+
+    /// Send the events of `topic` stored after version `since`, up to the latest stored event.
+    /// Events of documents that are archived now are left out unless `include_archived` is set.
+    /// Runs on the consumer thread, so no live event is sent in between.
+    fn replay(&self, ws_sender: &WsSender, conn_id: &str, topic: &str, since: &str, include_archived: bool) {
+        let unavailable = |message: &str| {
+            let _ = ws_sender.send(json!({"type": "replay_unavailable", "topic": topic, "since": since, "message": message}).to_string());
+        };
+        let (after, up_to) = match (self.lmdb_ctx.get_sequence(since), self.lmdb_ctx.latest_sequence()) {
+            (Ok(Some(after)), Ok(up_to)) => (after, up_to),
+            (Ok(None), _) => return unavailable("Unknown version"),
+            (Err(err), _) | (_, Err(err)) => {
+                error!("Error while reading sequence of version {} err={}", since, err);
+                return unavailable("Store error");
+            }
+        };
+
+        let events = self.lmdb_ctx.events_between(after, up_to, |evt| event_topic_keys(evt).iter().any(|key| key == topic))
+            .map(|events| events.into_iter()
+                .filter(|evt| include_archived || self.lmdb_ctx.get_current(evt.sys.id, false).is_ok())
+                .collect::<Vec<_>>());
+        match events {
+            Ok(events) => {
+                for evt in &events {
+                    match serde_json::to_string(evt) {
+                        Ok(val) => if let Err(err) = ws_sender.send(val) {
+                            warn!("Error while replaying event {} to conn_id {} err={}", evt.event_id, conn_id, err);
+                        },
+                        Err(err) => error!("Error in serialization {}", err)
+                    }
                 }
-            };
+                self.replayed.lock().unwrap().insert((String::from(conn_id), String::from(topic)), up_to);
+                let _ = ws_sender.send(json!({"type": "replayed", "topic": topic, "since": since, "count": events.len()}).to_string());
+            },
+            Err(err) => {
+                error!("Error while replaying topic {} since {} err={}", topic, since, err);
+                unavailable("Store error")
+            }
         }
-        Ok(())
     }
 } 
 
+/// Produce a command sent by a connection and send its result back on the same connection.
+/// The tracking id is always generated here, results are recorded by it and a client could
+/// otherwise pick the id of another client's command. What the client sent as tracking id is
+/// echoed as `client_tracking_id`.
+fn send_command(producer: &FutureProducer, kafka_settings: &KafkaSettings, results: &ResultListener, ws_sender: WsSender, command_json: &str, identity: &Identity) {
+    let command = match serde_json::from_str::<LedgerCommand<Value>>(command_json) {
+        Ok(command) => command,
+        Err(err) => {
+            error!("Error while parsing command={} err={}", command_json, err);
+            return;
+        }
+    };
+    // the result is matched by tracking_id, so it must be known before producing
+    let tracking_id = Uuid::new_v4().to_hyphenated().to_string();
+    let client_tracking_id = String::from(command.tracking_id);
+
+    let callback_tracking_id = tracking_id.clone();
+    let callback_client_tracking_id = client_tracking_id.clone();
+    let callback_sender = ws_sender.clone();
+    results.register(&tracking_id, move |result| {
+        let message = match result.and_then(|result_json| serde_json::from_str::<Value>(&result_json).ok()) {
+            Some(result) => json!({"type": "command_result", "client_tracking_id": callback_client_tracking_id, "result": result}),
+            None => json!({"type": "command_timeout", "client_tracking_id": callback_client_tracking_id, "tracking_id": callback_tracking_id})
+        };
+        if let Err(err) = callback_sender.send(message.to_string()) {
+            warn!("Error while sending result of tracking_id {} err={}", callback_tracking_id, err);
+        }
+    });
+    let message = match produce_command(producer, &kafka_settings.command_topic, LedgerCommand{tracking_id: &tracking_id, ..command}, identity).wait() {
+        Ok(_) => json!({"type": "command_sent", "client_tracking_id": client_tracking_id, "tracking_id": tracking_id}),
+        Err(message) => {
+            error!("{}", message);
+            results.unregister(&tracking_id);
+            json!({"type": "command_error", "client_tracking_id": client_tracking_id, "message": "Command could not be sent, retry later"})
+        }
+    };
+    if let Err(err) = ws_sender.send(message.to_string()) {
+        warn!("Error while sending command status of tracking_id {} err={}", tracking_id, err);
+    }
+}
+
 impl LedgerEvents for WsContext {
     fn on_event(&self, event: &LedgerEvent<Value>) {
         println!("Received event {} with checksum {} to WsContext", event.event_id, event.sys.payload_checksum.unwrap());
@@ -98,12 +272,55 @@ impl LedgerEvents for WsContext {
     }
     
     fn on_subscription_event(&self, event: &SubscriptionEvent) {
-        println!("Received event");
         match event {
-            SubscriptionEvent::Open{conn_id} => println!("S Opened conn_id {}", conn_id), 
-            SubscriptionEvent::Close{conn_id} => println!("S Closed conn_id {}", conn_id), 
-            SubscriptionEvent::Subscribe{conn_id, topic} => println!("S Opened conn_id {} topic {}", conn_id, topic), 
-            SubscriptionEvent::Unsubscribe{conn_id, topic} => println!("S Opened conn_id {} topic {}", conn_id, topic), 
+            SubscriptionEvent::Open{conn_id} => debug!("Opened conn_id {}", conn_id),
+            SubscriptionEvent::Close{conn_id} => {
+                debug!("Closed conn_id {}", conn_id);
+                let mut subscribers = self.subscribers.lock().unwrap();
+                for topic_conn_ids in subscribers.values_mut() {
+                    topic_conn_ids.remove(*conn_id);
+                }
+                subscribers.retain(|_, topic_conn_ids| !topic_conn_ids.is_empty());
+                self.replayed.lock().unwrap().retain(|(replayed_conn_id, _), _| replayed_conn_id != conn_id);
+                self.include_archived.lock().unwrap().retain(|(archived_conn_id, _)| archived_conn_id != conn_id);
+            },
+            SubscriptionEvent::Subscribe{conn_id, topic, since, include_archived} => {
+                // the stream carries subscriptions of every server, only keep our own connections
+                let ws_sender = match self.clients.lock().unwrap().get(*conn_id) {
+                    Some(ws_sender) => ws_sender.clone(),
+                    None => return
+                };
+                debug!("Subscribed conn_id {} topic {}", conn_id, topic);
+                let subscription = (String::from(*conn_id), String::from(*topic));
+                if *include_archived {
+                    self.include_archived.lock().unwrap().insert(subscription);
+                } else {
+                    self.include_archived.lock().unwrap().remove(&subscription);
+                }
+                if let Some(since_version) = since {
+                    self.replay(&ws_sender, conn_id, topic, since_version, *include_archived);
+                }
+                self.subscribers.lock().unwrap()
+                    .entry(String::from(*topic))
+                    .or_insert_with(HashSet::new)
+                    .insert(String::from(*conn_id));
+            },
+            SubscriptionEvent::Unsubscribe{conn_id, topic} => {
+                debug!("Unsubscribed conn_id {} topic {}", conn_id, topic);
+                let mut subscribers = self.subscribers.lock().unwrap();
+                let now_empty = match subscribers.get_mut(*topic) {
+                    Some(topic_conn_ids) => {
+                        topic_conn_ids.remove(*conn_id);
+                        topic_conn_ids.is_empty()
+                    },
+                    None => false
+                };
+                if now_empty {
+                    subscribers.remove(*topic);
+                }
+                self.replayed.lock().unwrap().remove(&(String::from(*conn_id), String::from(*topic)));
+                self.include_archived.lock().unwrap().remove(&(String::from(*conn_id), String::from(*topic)));
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,23 @@
+use std::process::{Command, Output};
+
+fn toamend(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_toamend"))
+        .args(args)
+        .output()
+        .expect("Could not run toamend")
+}
+
+#[test]
+fn every_process_is_a_subcommand() {
+    let help = String::from_utf8(toamend(&["--help"]).stdout).unwrap();
+    for subcommand in &["cmd-worker", "event-consumer", "ws-server", "send-command"] {
+        assert!(help.contains(subcommand), "{} is missing from\n{}", subcommand, help);
+    }
+}
+
+#[test]
+fn revision_commands_need_id_and_version() {
+    let output = toamend(&["send-command", "--action", "publish", "--token", "token", "--version", "v1"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("--id is required for this action"));
+}